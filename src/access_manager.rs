@@ -1,91 +1,479 @@
 use std::borrow::Borrow;
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::path::Path;
-use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard};
 
 use anyhow::{anyhow, Context, Result};
 use thiserror::Error;
 
 use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, SlottedPage};
-use crate::buffer_manager::{BufferError, BufferId, PageBuffer};
+use crate::buffer_manager::{BufferError, Lookup, PageLoad, PageReadGuard, PageWriteGuard};
+use crate::buffer_manager::access_strategy::{AccessStrategy, StrategyKind};
+use crate::buffer_manager::replacement_policy::Policy;
 
 use super::buffer_manager::BufferManager;
-use super::disk_manager::{DiskManager, PageId};
+use super::disk_manager::{DiskManager, PageId, PageStore};
+use super::disk_manager::file_store::FileStore;
 
-pub struct AccessManager {
-    //FIXME:
-    disk_manager: DiskManager,
+pub mod background_writer;
+pub mod checkpointer;
+pub mod worker;
+
+// The number of pages a checkpoint writes at a time
+const CHECKPOINT_BATCH_SIZE: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct AccessOptions {
+    // The number of pages the buffer pool holds
+    pub pool_size: usize,
+    pub replacement_policy: Policy,
+}
+
+impl Default for AccessOptions {
+    fn default() -> Self {
+        Self {
+            pool_size: 10,
+            replacement_policy: Policy::default(),
+        }
+    }
+}
+
+pub struct AccessManager<S: PageStore> {
+    disk_manager: Mutex<DiskManager<S>>,
     buffer_manager: BufferManager,
-    buffer_table: HashMap<PageId, BufferId>,
 }
 
-impl AccessManager {
+impl AccessManager<FileStore> {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
-        let mut disk_manager = DiskManager::new(path).context("failed to new disk manager")?;
-        let mut buffer_manager = BufferManager::new(10);
-        let table = HashMap::new();
+        let store = FileStore::open(path).context("failed to open the file store")?;
+        Self::open(store)
+    }
+}
+
+impl<S: PageStore> AccessManager<S> {
+    pub fn open(store: S) -> Result<Self> {
+        Self::with_options(store, AccessOptions::default())
+    }
+
+    pub fn with_options(store: S, options: AccessOptions) -> Result<Self> {
+        let disk_manager = DiskManager::open(store).context("failed to new disk manager")?;
+        let buffer_manager = BufferManager::with_policy(options.pool_size, disk_manager.page_size(), options.replacement_policy).context("failed to new buffer manager")?;
         Ok(Self {
-            disk_manager,
+            disk_manager: Mutex::new(disk_manager),
             buffer_manager,
-            buffer_table: table,
         })
     }
 
-    pub fn initialize(&mut self) -> Result<()> {
-        let page_id = PageId(0);
-        let ret = self.disk_manager.fetch_page(page_id);
-        let p = match ret {
-            Ok(p) => {
-                p
-            }
-            Err(_) => {
-                SlottedPage::new(MAGIC_NUMBER_LEAF)
+    // A panic while the disk manager is locked leaves the file header in an unknown state, so it is propagated
+    fn disk_manager(&self) -> MutexGuard<'_, DiskManager<S>> {
+        self.disk_manager.lock().unwrap()
+    }
+
+    pub fn initialize(&self) -> Result<()> {
+        let page_id = {
+            let mut disk_manager = self.disk_manager();
+            let mut page_id = *disk_manager.root_page_id();
+            if !page_id.is_valid() {
+                page_id = disk_manager.allocate_page().context("failed to allocate the root page")?;
+                let p = SlottedPage::new(disk_manager.page_size(), MAGIC_NUMBER_LEAF).context("failed to new the root page")?;
+                disk_manager.write_page(page_id, &p).context("failed to write the root page")?;
+                disk_manager.set_root_page_id(page_id).context("failed to set the root page id")?;
             }
+            page_id
         };
-        let buffer_id = self.buffer_manager.add_page(p).context("failed to add a page")?;
-        self.buffer_table.insert(page_id, buffer_id);
+        self.fetch_page(page_id).context("failed to read the root page")?;
         Ok(())
     }
 
-    pub fn fetch_page(&mut self, page_id: PageId) -> Result<Rc<PageBuffer>> {
-        if let Some(&buffer_id) = self.buffer_table.get(&page_id) {
-            if let Some(buffer) = self.buffer_manager.fetch_page(buffer_id) {
-                return Ok(buffer);
+    pub fn root_page_id(&self) -> PageId {
+        *self.disk_manager().root_page_id()
+    }
+
+    // The page stays in the buffer pool while the guard is alive.
+    // Threads missing the same page at once read it from the disk only once.
+    pub fn fetch_page(&self, page_id: PageId) -> Result<PageReadGuard> {
+        self.fetch(page_id, None)
+    }
+
+    pub fn fetch_page_mut(&self, page_id: PageId) -> Result<PageWriteGuard> {
+        Ok(self.fetch_page(page_id)?.into_write())
+    }
+
+    // A large scan or a bulk load requests a strategy, and loads its pages into the small ring of
+    // the strategy instead of evicting the pages the others are working on
+    pub fn strategy(&self, kind: StrategyKind) -> AccessStrategy {
+        self.buffer_manager.strategy(kind)
+    }
+
+    pub fn fetch_page_with_strategy(&self, page_id: PageId, strategy: &mut AccessStrategy) -> Result<PageReadGuard> {
+        self.fetch(page_id, Some(strategy))
+    }
+
+    pub fn fetch_page_mut_with_strategy(&self, page_id: PageId, strategy: &mut AccessStrategy) -> Result<PageWriteGuard> {
+        Ok(self.fetch(page_id, Some(strategy))?.into_write())
+    }
+
+    fn fetch(&self, page_id: PageId, strategy: Option<&mut AccessStrategy>) -> Result<PageReadGuard> {
+        match self.buffer_manager.lookup(page_id) {
+            Lookup::Hit(guard) => Ok(guard),
+            Lookup::Miss(load) => {
+                let page = self.disk_manager().fetch_page(page_id)
+                    .with_context(|| format!("failed to find the page with {:?}", page_id))?;
+                self.finish_load(load, page, strategy)
+            }
+            Lookup::Loading => unreachable!("lookup() waits for the page being loaded"),
+        }
+    }
+
+    // Pages missing in the buffer pool are read from the disk in one batch.
+    // All of them are pinned at once, so that the batch fails with NoFreeBuffer when it does not fit in the pool.
+    pub fn fetch_pages(&self, page_ids: &[PageId]) -> Result<Vec<PageReadGuard>> {
+        let mut guards = HashMap::new();
+        let mut loads = vec![];
+        let mut loading = vec![];
+        for &page_id in page_ids {
+            if guards.contains_key(&page_id) || loads.iter().any(|load: &PageLoad| load.page_id() == page_id) || loading.contains(&page_id) {
+                continue;
+            }
+            match self.buffer_manager.try_lookup(page_id) {
+                Lookup::Hit(guard) => {
+                    guards.insert(page_id, guard);
+                }
+                Lookup::Miss(load) => loads.push(load),
+                Lookup::Loading => loading.push(page_id),
+            }
+        }
+        let missing: Vec<PageId> = loads.iter().map(|load| load.page_id()).collect();
+        let pages = self.disk_manager().fetch_pages(&missing)
+            .with_context(|| format!("failed to find the pages with {:?}", missing))?;
+        for (load, page) in loads.into_iter().zip(pages) {
+            let page_id = load.page_id();
+            let guard = self.finish_load(load, page, None)?;
+            guards.insert(page_id, guard);
+        }
+        // Waited for only after our own loads have finished, since the loading thread may be waiting for them
+        for page_id in loading {
+            let guard = self.fetch_page(page_id)?;
+            guards.insert(page_id, guard);
+        }
+
+        // The guards hold the latches, so a page is latched again only when it is requested twice
+        let mut fetched = vec![];
+        for &page_id in page_ids {
+            match guards.remove(&page_id) {
+                Some(guard) => fetched.push(guard),
+                None => fetched.push(self.buffer_manager.fetch_page(page_id).unwrap()),
             }
         }
-        let page = self.disk_manager.fetch_page(page_id)
-            .with_context(|| format!("failed to find the page with {:?}", page_id))?;
-        let buffer_id = self.buffer_manager.add_page(page).context("failed to add the page")?;
-        self.buffer_table.insert(page_id, buffer_id);
-        let page_buffer = self.buffer_manager.fetch_page(buffer_id).unwrap();
+        Ok(fetched)
+    }
 
-        Ok(page_buffer)
+    // Writes every dirty page in one batch, and makes them durable
+    pub fn flush_all(&self) -> Result<()> {
+        self.buffer_manager.flush_all(|pages| self.disk_manager().write_pages(pages))
+            .context("failed to write dirty pages")?;
+        self.finish_write_backs()?;
+
+        self.disk_manager().sync().context("failed to sync dirty pages")
+    }
+
+    // Writes the dirty pages among the next max_pages pages to be evicted, a round of the background writer
+    pub fn clean_ahead(&self, max_pages: usize) -> Result<usize> {
+        self.buffer_manager.clean_ahead(max_pages, |pages| self.disk_manager().write_pages(pages))
+            .context("failed to write dirty pages ahead of eviction")
+    }
+
+    pub fn checkpoint(&self) -> Result<u32> {
+        self.checkpoint_with(|_| {})
+    }
+
+    // Writes the pages dirty at the start in batches, and records the checkpoint in the file header
+    // once they are durable. throttle is called with the number of pages written after every batch.
+    pub fn checkpoint_with(&self, mut throttle: impl FnMut(usize)) -> Result<u32> {
+        let page_ids = self.buffer_manager.dirty_page_ids();
+        for batch in page_ids.chunks(CHECKPOINT_BATCH_SIZE) {
+            let written = self.buffer_manager.flush_pages(batch, |pages| self.disk_manager().write_pages(pages))
+                .context("failed to write dirty pages for the checkpoint")?;
+            throttle(written);
+        }
+        self.finish_write_backs()?;
+
+        self.disk_manager().checkpoint().context("failed to record the checkpoint")
+    }
+
+    // Evictions and the background writer mark pages clean before their writes land, so the writes in
+    // progress are waited for. A page whose write has failed is dirty again, and is written here.
+    fn finish_write_backs(&self) -> Result<()> {
+        let page_ids = self.buffer_manager.wait_for_write_backs();
+        self.buffer_manager.flush_pages(&page_ids, |pages| self.disk_manager().write_pages(pages))
+            .context("failed to write back dirty pages")?;
+        Ok(())
+    }
+
+    fn finish_load(&self, load: PageLoad<'_>, page: SlottedPage, strategy: Option<&mut AccessStrategy>) -> Result<PageReadGuard> {
+        let write_back = |page_id, page: &SlottedPage| self.disk_manager().write_page(page_id, page);
+        let guard = match strategy {
+            Some(strategy) => load.finish_with_strategy(page, strategy, write_back),
+            None => load.finish(page, write_back),
+        }.context("failed to add the page")?;
+
+        Ok(guard)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
 
-    use super::AccessManager;
+    use crate::buffer_manager::BufferError;
+    use crate::buffer_manager::access_strategy::StrategyKind;
+    use crate::buffer_manager::replacement_policy::Policy;
+    use crate::disk_manager::memory_store::MemoryStore;
+    use crate::test_util::{leaf_page, random_indices, write_new_pages};
 
-    const DB_PATH: &str = "test_access_manager.idb";
+    use super::{AccessManager, AccessOptions};
 
-    struct Cleanup;
+    #[test]
+    fn test() {
+        let ret = AccessManager::open(MemoryStore::new());
+        assert_eq!(ret.is_ok(), true);
+        let manager = ret.unwrap();
+        assert_eq!(manager.initialize().is_ok(), true);
+        let root_page_id = manager.root_page_id();
+        assert_eq!(root_page_id.is_valid(), true);
+        assert_eq!(manager.fetch_page(root_page_id).is_ok(), true);
 
-    impl Drop for Cleanup {
-        fn drop(&mut self) {
-            fs::remove_file(DB_PATH).expect("failed to remove db file");
-        }
+        // The root page id survives a restart
+        let store = manager.disk_manager.into_inner().unwrap().into_store();
+        let manager = AccessManager::open(store).unwrap();
+        assert_eq!(manager.root_page_id(), root_page_id);
+        assert_eq!(manager.initialize().is_ok(), true);
+        assert_eq!(manager.root_page_id(), root_page_id);
+
+        let options = AccessOptions { replacement_policy: Policy::LruK(0), ..Default::default() };
+        let err = AccessManager::with_options(MemoryStore::new(), options).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<BufferError>(), Some(BufferError::InvalidPolicy(Policy::LruK(0)))), true);
     }
 
     #[test]
-    fn test() {
-        let cleanup = Cleanup;
-        let ret = AccessManager::new(DB_PATH);
+    fn test_fetch_pages() {
+        let manager = AccessManager::open(MemoryStore::new()).unwrap();
+        assert_eq!(manager.initialize().is_ok(), true);
+        let root_page_id = manager.root_page_id();
+        let mut page_ids = vec![root_page_id];
+        page_ids.extend(write_new_pages(&mut manager.disk_manager(), (0..3_u8).map(|i| leaf_page(Some((&[i], b"value"))))));
+        page_ids.push(root_page_id);
+
+        let ret = manager.fetch_pages(&page_ids);
         assert_eq!(ret.is_ok(), true);
-        let mut manager = ret.unwrap();
+        let buffers = ret.unwrap();
+        assert_eq!(buffers.len(), 5);
+        assert_eq!(buffers[0].buffer_id(), buffers[4].buffer_id());
+        assert_eq!(buffers[0].page().empty(), true);
+        for i in 0..3_u8 {
+            assert_eq!(buffers[i as usize + 1].page().cell_view(0).body()[0], i);
+        }
+    }
+
+    #[test]
+    fn test_pinned_pages_are_not_evicted() {
+        let manager = AccessManager::open(MemoryStore::new()).unwrap();
+        let page_ids = write_new_pages(&mut manager.disk_manager(), (0..11_u8).map(|i| leaf_page(Some((&[i], b"value")))));
+
+        // The pool holds 10 pages
+        let guards = manager.fetch_pages(&page_ids[..10]).unwrap();
+        let err = manager.fetch_page(page_ids[10]).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<BufferError>(), Some(BufferError::NoFreeBuffer)), true);
+        for (i, guard) in guards.iter().enumerate() {
+            assert_eq!(guard.page().cell_view(0).body()[0], i as u8);
+        }
+
+        drop(guards);
+        let guard = manager.fetch_page(page_ids[10]).unwrap();
+        assert_eq!(guard.page().cell_view(0).body()[0], 10);
+    }
+
+    #[test]
+    fn test_flush_all() {
+        let manager = AccessManager::open(MemoryStore::new()).unwrap();
         assert_eq!(manager.initialize().is_ok(), true);
+        let root_page_id = manager.root_page_id();
+        let mut guard = manager.fetch_page_mut(root_page_id).unwrap();
+        guard.page_mut().add_cell(0, b"key", b"value").unwrap();
+        drop(guard);
+        // Not written yet
+        assert_eq!(manager.disk_manager().fetch_page(root_page_id).unwrap().empty(), true);
+
+        assert_eq!(manager.flush_all().is_ok(), true);
+        assert_eq!(manager.buffer_manager.dirty_page_ids().len(), 0);
+        let store = manager.disk_manager.into_inner().unwrap().into_store();
+        let manager = AccessManager::open(store).unwrap();
+        assert_eq!(manager.initialize().is_ok(), true);
+        let guard = manager.fetch_page(root_page_id).unwrap();
+        assert_eq!(guard.page().cell_view(0).body(), b"keyvalue");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_write_back_on_eviction() {
+        let manager = AccessManager::open(MemoryStore::new()).unwrap();
+        let page_ids = write_new_pages(&mut manager.disk_manager(), (0..20).map(|_| leaf_page(None)));
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            let mut guard = manager.fetch_page_mut(page_id).unwrap();
+            guard.page_mut().add_cell(0, &[i as u8], b"value").unwrap();
+        }
+
+        // The pool holds 10 pages, so the first half has been evicted and written back
+        for (i, &page_id) in page_ids[..10].iter().enumerate() {
+            let page = manager.disk_manager().fetch_page(page_id).unwrap();
+            assert_eq!(page.cell_view(0).body()[0], i as u8);
+        }
+        for &page_id in page_ids[10..].iter() {
+            assert_eq!(manager.disk_manager().fetch_page(page_id).unwrap().empty(), true);
+        }
+    }
+
+    #[test]
+    fn test_churn() {
+        for policy in Policy::ALL {
+            let options = AccessOptions { replacement_policy: policy, ..Default::default() };
+            let manager = AccessManager::with_options(MemoryStore::new(), options).unwrap();
+            let page_ids = write_new_pages(&mut manager.disk_manager(), (0..64_u8).map(|i| leaf_page(Some((&[i], &0_u32.to_be_bytes())))));
+
+            // The pool holds 10 pages, so almost every access evicts another page
+            let mut counters = vec![0_u32; page_ids.len()];
+            for (round, i) in random_indices(7, page_ids.len()).take(1000).enumerate() {
+                if round % 2 == 0 {
+                    let mut guard = manager.fetch_page_mut(page_ids[i]).unwrap();
+                    assert_eq!(guard.page_id(), page_ids[i]);
+                    let page = guard.page_mut();
+                    let mut cell = page.cell_view_mut(0);
+                    assert_eq!(cell.body()[0], i as u8, "{:?}", policy);
+                    counters[i] += 1;
+                    cell.body_mut()[1..].copy_from_slice(&counters[i].to_be_bytes());
+                } else {
+                    let batch: Vec<_> = (0..3).map(|j| page_ids[(i + j * 7) % page_ids.len()]).collect();
+                    for (j, guard) in manager.fetch_pages(&batch).unwrap().iter().enumerate() {
+                        let i = (i + j * 7) % page_ids.len();
+                        assert_eq!(guard.page_id(), page_ids[i]);
+                        let page = guard.page();
+                        assert_eq!(page.cell_view(0).body()[0], i as u8, "{:?}", policy);
+                        assert_eq!(page.cell_view(0).body()[1..], counters[i].to_be_bytes(), "{:?}", policy);
+                    }
+                }
+            }
+
+            assert_eq!(manager.flush_all().is_ok(), true);
+            for (i, &page_id) in page_ids.iter().enumerate() {
+                let page = manager.disk_manager().fetch_page(page_id).unwrap();
+                assert_eq!(page.cell_view(0).body()[1..], counters[i].to_be_bytes(), "{:?}", policy);
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_with_strategy() {
+        let options = AccessOptions { pool_size: 16, ..Default::default() };
+        let manager = AccessManager::with_options(MemoryStore::new(), options).unwrap();
+        let page_ids = write_new_pages(&mut manager.disk_manager(), (0..40_u8).map(|i| leaf_page(Some((&[i], b"value")))));
+        let (hot_page_ids, table_page_ids) = page_ids.split_at(8);
+        for &page_id in hot_page_ids {
+            manager.fetch_page(page_id).unwrap();
+        }
+
+        // The scan goes through a ring of 2 frames, an eighth of the pool
+        let mut strategy = manager.strategy(StrategyKind::BulkRead);
+        assert_eq!(strategy.ring_size(), 2);
+        for (i, &page_id) in table_page_ids.iter().enumerate() {
+            let guard = manager.fetch_page_with_strategy(page_id, &mut strategy).unwrap();
+            assert_eq!(guard.page().cell_view(0).body()[0], i as u8 + 8);
+        }
+        for &page_id in hot_page_ids {
+            assert_eq!(manager.buffer_manager.fetch_page(page_id).is_some(), true);
+        }
+
+        // A bulk load writes back the pages it has modified as the ring goes round
+        let mut strategy = manager.strategy(StrategyKind::BulkWrite);
+        for &page_id in table_page_ids {
+            let mut guard = manager.fetch_page_mut_with_strategy(page_id, &mut strategy).unwrap();
+            guard.page_mut().add_cell(1, b"key", b"value").unwrap();
+        }
+        for &page_id in hot_page_ids {
+            assert_eq!(manager.buffer_manager.fetch_page(page_id).is_some(), true);
+        }
+        let page = manager.disk_manager().fetch_page(table_page_ids[0]).unwrap();
+        assert_eq!(page.cell_view(1).body(), b"keyvalue");
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let options = AccessOptions { pool_size: 40, ..Default::default() };
+        let manager = AccessManager::with_options(MemoryStore::new(), options.clone()).unwrap();
+        let page_ids = write_new_pages(&mut manager.disk_manager(), (0..40).map(|_| leaf_page(None)));
+        for &page_id in page_ids.iter() {
+            let mut guard = manager.fetch_page_mut(page_id).unwrap();
+            guard.page_mut().add_cell(0, b"key", b"value").unwrap();
+        }
+
+        let mut batches = vec![];
+        assert_eq!(manager.checkpoint_with(|written| batches.push(written)).unwrap(), 1);
+        assert_eq!(batches, vec![16, 16, 8]);
+        assert_eq!(manager.buffer_manager.dirty_page_ids().len(), 0);
+
+        let store = manager.disk_manager.into_inner().unwrap().into_store();
+        let manager = AccessManager::with_options(store, options).unwrap();
+        assert_eq!(manager.disk_manager().checkpoint_id(), 1);
+        for &page_id in page_ids.iter() {
+            assert_eq!(manager.fetch_page(page_id).unwrap().page().cell_view(0).body(), b"keyvalue");
+        }
+    }
+
+    #[test]
+    fn test_concurrent_access() {
+        for policy in Policy::ALL {
+            let options = AccessOptions { replacement_policy: policy, ..Default::default() };
+            let manager = Arc::new(AccessManager::with_options(MemoryStore::new(), options).unwrap());
+            let page_ids = write_new_pages(&mut manager.disk_manager(), (0..20).map(|_| leaf_page(Some((b"", &0_u64.to_be_bytes())))));
+
+            // The pool holds 10 pages, so the threads keep evicting the pages of each other
+            let threads: Vec<_> = (0..4).map(|t| {
+                let manager = Arc::clone(&manager);
+                let page_ids = page_ids.clone();
+                thread::spawn(move || {
+                    for i in 0..500 {
+                        let mut guard = manager.fetch_page_mut(page_ids[(i * 3 + t) % page_ids.len()]).unwrap();
+                        let page = guard.page_mut();
+                        let mut cell = page.cell_view_mut(0);
+                        let counter = u64::from_be_bytes(cell.body()[..8].try_into().unwrap());
+                        cell.body_mut()[..8].copy_from_slice(&(counter + 1).to_be_bytes());
+                    }
+                })
+            }).collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            assert_eq!(manager.flush_all().is_ok(), true);
+            let mut total = 0;
+            for &page_id in page_ids.iter() {
+                let page = manager.disk_manager().fetch_page(page_id).unwrap();
+                total += u64::from_be_bytes(page.cell_view(0).body()[..8].try_into().unwrap());
+            }
+            assert_eq!(total, 4 * 500, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test_access_manager.idb");
+        let manager = AccessManager::new(&path).unwrap();
+        assert_eq!(manager.initialize().is_ok(), true);
+        let root_page_id = manager.root_page_id();
+        drop(manager);
+
+        let manager = AccessManager::new(&path).unwrap();
+        assert_eq!(manager.initialize().is_ok(), true);
+        assert_eq!(manager.root_page_id(), root_page_id);
+    }
+}
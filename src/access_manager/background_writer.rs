@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::access_manager::AccessManager;
+use crate::access_manager::worker::Worker;
+use crate::disk_manager::PageStore;
+
+#[derive(Debug, Clone)]
+pub struct BackgroundWriterOptions {
+    // The pause between rounds
+    pub interval: Duration,
+    // The most pages written in a round, which limits the writes to max_pages per interval
+    pub max_pages: usize,
+}
+
+impl Default for BackgroundWriterOptions {
+    // The defaults of PostgreSQL's bgwriter_delay and bgwriter_lru_maxpages
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(200),
+            max_pages: 100,
+        }
+    }
+}
+
+// Trickles dirty pages out ahead of the replacement policy, so that a thread loading a page
+// rarely has to write back the victim on its own
+pub struct BackgroundWriter {
+    worker: Worker,
+}
+
+impl BackgroundWriter {
+    pub fn start<S: PageStore + Send + 'static>(manager: Arc<AccessManager<S>>, options: BackgroundWriterOptions) -> Result<Self> {
+        let worker = Worker::spawn("background writer", options.interval, move |_| {
+            manager.clean_ahead(options.max_pages)?;
+            Ok(())
+        })?;
+        Ok(Self { worker })
+    }
+
+    // The number of rounds failed in a row, e.g. while the disk is full
+    pub fn consecutive_failures(&self) -> u32 {
+        self.worker.consecutive_failures()
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.worker.last_error()
+    }
+
+    pub fn stop(self) -> Result<()> {
+        self.worker.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::access_manager::AccessOptions;
+    use crate::buffer_manager::replacement_policy::Policy;
+    use crate::disk_manager::memory_store::MemoryStore;
+    use crate::test_util::{leaf_page, write_new_pages};
+
+    use super::*;
+
+    #[test]
+    fn test_background_writer() {
+        let options = AccessOptions { replacement_policy: Policy::Lru, ..Default::default() };
+        let manager = Arc::new(AccessManager::with_options(MemoryStore::new(), options).unwrap());
+        let page_ids = write_new_pages(&mut manager.disk_manager(), (0..10).map(|_| leaf_page(None)));
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            let mut guard = manager.fetch_page_mut(page_id).unwrap();
+            guard.page_mut().add_cell(0, &[i as u8], b"value").unwrap();
+        }
+
+        // A round writes 4 pages at most, from the least recently used one
+        assert_eq!(manager.clean_ahead(4).unwrap(), 4);
+        for (i, &page_id) in page_ids[..4].iter().enumerate() {
+            assert_eq!(manager.disk_manager().fetch_page(page_id).unwrap().cell_view(0).body()[0], i as u8);
+        }
+        assert_eq!(manager.disk_manager().fetch_page(page_ids[4]).unwrap().empty(), true);
+
+        // The writer looks 8 frames ahead, and the 2 most recently used pages stay dirty
+        let options = BackgroundWriterOptions { interval: Duration::from_millis(1), max_pages: 8 };
+        let writer = BackgroundWriter::start(Arc::clone(&manager), options).unwrap();
+        while manager.buffer_manager.dirty_page_ids().len() > 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(writer.stop().is_ok(), true);
+        for (i, &page_id) in page_ids[..8].iter().enumerate() {
+            assert_eq!(manager.disk_manager().fetch_page(page_id).unwrap().cell_view(0).body()[0], i as u8);
+        }
+        let mut dirty_page_ids = manager.buffer_manager.dirty_page_ids();
+        dirty_page_ids.sort_by_key(|page_id| page_id.0);
+        assert_eq!(dirty_page_ids, page_ids[8..].to_vec());
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::access_manager::AccessManager;
+use crate::access_manager::worker::Worker;
+use crate::disk_manager::PageStore;
+
+#[derive(Debug, Clone)]
+pub struct CheckpointerOptions {
+    // The pause between checkpoints
+    pub interval: Duration,
+    // The most pages written per second, so that a checkpoint does not starve the other I/O.
+    // 0 writes them as fast as possible.
+    pub max_pages_per_second: usize,
+}
+
+impl Default for CheckpointerOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            max_pages_per_second: 1000,
+        }
+    }
+}
+
+// Writes every dirty page, syncs and records a checkpoint in the file header at an interval.
+// The writes are spread out by the rate limit, which is lifted once the checkpointer is stopped
+// so that the checkpoint in progress finishes quickly.
+pub struct Checkpointer {
+    worker: Worker,
+}
+
+impl Checkpointer {
+    pub fn start<S: PageStore + Send + 'static>(manager: Arc<AccessManager<S>>, options: CheckpointerOptions) -> Result<Self> {
+        let worker = Worker::spawn("checkpointer", options.interval, move |signal| {
+            let rate = options.max_pages_per_second;
+            manager.checkpoint_with(|written| {
+                if rate > 0 {
+                    signal.sleep(Duration::from_secs_f64(written as f64 / rate as f64));
+                }
+            })?;
+            Ok(())
+        })?;
+        Ok(Self { worker })
+    }
+
+    // The number of rounds failed in a row, e.g. while the disk is full
+    pub fn consecutive_failures(&self) -> u32 {
+        self.worker.consecutive_failures()
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.worker.last_error()
+    }
+
+    pub fn stop(self) -> Result<()> {
+        self.worker.stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+
+    use crate::disk_manager::memory_store::MemoryStore;
+    use crate::test_util::{leaf_page, write_new_pages};
+
+    use super::*;
+
+    fn dirty_manager() -> Arc<AccessManager<MemoryStore>> {
+        let manager = Arc::new(AccessManager::open(MemoryStore::new()).unwrap());
+        let page_ids = write_new_pages(&mut manager.disk_manager(), (0..10).map(|_| leaf_page(None)));
+        for page_id in page_ids {
+            let mut guard = manager.fetch_page_mut(page_id).unwrap();
+            guard.page_mut().add_cell(0, b"key", b"value").unwrap();
+        }
+        manager
+    }
+
+    #[test]
+    fn test_checkpointer() {
+        let manager = dirty_manager();
+        let options = CheckpointerOptions { interval: Duration::from_millis(1), max_pages_per_second: 0 };
+        let checkpointer = Checkpointer::start(Arc::clone(&manager), options).unwrap();
+        while manager.disk_manager().checkpoint_id() < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(checkpointer.stop().is_ok(), true);
+        assert_eq!(manager.buffer_manager.dirty_page_ids().len(), 0);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        // 10 pages at 100 pages per second take 100ms
+        let manager = dirty_manager();
+        let options = CheckpointerOptions { interval: Duration::from_millis(1), max_pages_per_second: 100 };
+        let start = Instant::now();
+        let checkpointer = Checkpointer::start(Arc::clone(&manager), options).unwrap();
+        while manager.disk_manager().checkpoint_id() < 1 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(start.elapsed() >= Duration::from_millis(100), true);
+        assert_eq!(checkpointer.stop().is_ok(), true);
+
+        // Stopping lifts the limit
+        let manager = dirty_manager();
+        let options = CheckpointerOptions { interval: Duration::from_millis(1), max_pages_per_second: 1 };
+        let checkpointer = Checkpointer::start(Arc::clone(&manager), options).unwrap();
+        while manager.buffer_manager.dirty_page_ids().len() == 10 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let start = Instant::now();
+        assert_eq!(checkpointer.stop().is_ok(), true);
+        assert_eq!(start.elapsed() < Duration::from_secs(5), true);
+        assert_eq!(manager.disk_manager().checkpoint_id(), 1);
+    }
+}
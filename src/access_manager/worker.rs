@@ -0,0 +1,161 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+// The interval is doubled after each failed round up to this many times
+const MAX_BACKOFF_SHIFT: u32 = 6;
+
+// A thread doing a round of work at an interval until it is stopped.
+// A failed round is retried with a backoff, and the error of the last round is kept for
+// last_error() and returned by stop() when it failed.
+pub struct Worker {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+    // The number of rounds failed in a row, and 0 once a round succeeds
+    failures: Arc<AtomicU32>,
+    // The error of the last round, and None once a round succeeds
+    last_error: Arc<Mutex<Option<anyhow::Error>>>,
+}
+
+// Wakes the worker up as soon as it is stopped
+pub struct StopSignal(Receiver<()>);
+
+impl StopSignal {
+    // Returns true without waiting for the timeout once the worker has been stopped
+    pub fn sleep(&self, timeout: Duration) -> bool {
+        // Nothing is ever sent, and the channel is disconnected on stop
+        !matches!(self.0.recv_timeout(timeout), Err(RecvTimeoutError::Timeout))
+    }
+}
+
+impl Worker {
+    pub fn spawn<F>(name: &str, interval: Duration, mut round: F) -> Result<Self>
+    where
+        F: FnMut(&StopSignal) -> Result<()> + Send + 'static,
+    {
+        let (stop, receiver) = mpsc::channel();
+        let signal = StopSignal(receiver);
+        let failures = Arc::new(AtomicU32::new(0));
+        let last_error = Arc::new(Mutex::new(None));
+        let handle = {
+            let failures = Arc::clone(&failures);
+            let last_error = Arc::clone(&last_error);
+            thread::Builder::new()
+                .name(name.to_string())
+                .spawn(move || {
+                    while !signal.sleep(interval * (1 << failures.load(Ordering::Relaxed).min(MAX_BACKOFF_SHIFT))) {
+                        match round(&signal) {
+                            Ok(()) => {
+                                failures.store(0, Ordering::Relaxed);
+                                *last_error.lock().unwrap() = None;
+                            }
+                            Err(err) => {
+                                failures.fetch_add(1, Ordering::Relaxed);
+                                *last_error.lock().unwrap() = Some(err);
+                            }
+                        }
+                    }
+                })
+                .with_context(|| format!("failed to spawn the {}", name))?
+        };
+
+        Ok(Self { stop: Some(stop), handle: Some(handle), failures, last_error })
+    }
+
+    // 0 while the worker is healthy
+    pub fn consecutive_failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    // The error of the last round with its causes, while the worker is failing
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().as_ref().map(|err| format!("{:#}", err))
+    }
+
+    // Waits for the round in progress
+    pub fn stop(mut self) -> Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<()> {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            handle.join().map_err(|_| anyhow!("the worker panicked"))?;
+        }
+        match self.last_error.lock().unwrap().take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_stop() {
+        let rounds = Arc::new(AtomicUsize::new(0));
+        let worker = {
+            let rounds = Arc::clone(&rounds);
+            Worker::spawn("test worker", Duration::from_millis(1), move |_| {
+                rounds.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }).unwrap()
+        };
+        while rounds.load(Ordering::Relaxed) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(worker.stop().is_ok(), true);
+
+        // A sleeping worker is woken up
+        let worker = Worker::spawn("test worker", Duration::from_secs(3600), |_| Ok(())).unwrap();
+        let start = Instant::now();
+        assert_eq!(worker.stop().is_ok(), true);
+        assert_eq!(start.elapsed() < Duration::from_secs(60), true);
+    }
+
+    #[test]
+    fn test_error() {
+        let worker = Worker::spawn("test worker", Duration::from_millis(1), |_| Err(anyhow!("disk full"))).unwrap();
+        // The worker keeps running, and backs off
+        while worker.consecutive_failures() < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(worker.handle.as_ref().unwrap().is_finished(), false);
+        assert_eq!(worker.last_error(), Some("disk full".to_string()));
+        assert_eq!(worker.stop().err().unwrap().to_string(), "disk full");
+
+        // A round which succeeds resets the failures
+        let rounds = Arc::new(AtomicUsize::new(0));
+        let worker = {
+            let rounds = Arc::clone(&rounds);
+            Worker::spawn("test worker", Duration::from_millis(1), move |_| {
+                match rounds.fetch_add(1, Ordering::Relaxed) {
+                    0 | 1 => Err(anyhow!("disk full")),
+                    _ => Ok(()),
+                }
+            }).unwrap()
+        };
+        while rounds.load(Ordering::Relaxed) < 4 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(worker.consecutive_failures(), 0);
+        assert_eq!(worker.last_error(), None);
+        assert_eq!(worker.stop().is_ok(), true);
+    }
+}
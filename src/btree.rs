@@ -1,11 +1,11 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
 use anyhow::Context;
 use thiserror::Error;
 
 use crate::access_manager::AccessManager;
 use crate::btree::node::Node;
-use crate::disk_manager::PageId;
+use crate::disk_manager::{PageId, PageStore};
 
 pub mod slotted_page;
 pub mod node;
@@ -13,14 +13,12 @@ pub mod node;
 #[derive(Debug, Error)]
 pub enum Error {}
 
-struct Btree {
-    access_manager: Rc<AccessManager>,
+struct Btree<S: PageStore> {
+    access_manager: Arc<AccessManager<S>>,
 }
 
-const ROOT_PAGE_ID: PageId = PageId(0);
-
-impl Btree {
-    pub fn new(access_manager: Rc<AccessManager>) -> Self {
+impl<S: PageStore> Btree<S> {
+    pub fn new(access_manager: Arc<AccessManager<S>>) -> Self {
         Self {
             access_manager
         }
@@ -39,7 +37,7 @@ impl Btree {
     // * Cellのフラグメンテーションの扱いも考慮する
     // * 右端のポインタ含め、Node上のkey:valueの配置を確定させる
     // pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
-    //     let mut current_page = self.access_manager.fetch_page(ROOT_PAGE_ID).context("failed to find the root page")?;
+    //     let mut current_page = self.access_manager.fetch_page(self.access_manager.root_page_id()).context("failed to find the root page")?;
     //     let mut node = Node::new(current_page);
     //     loop {
     //         let (index, _) = node.find(key);
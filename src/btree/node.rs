@@ -1,16 +1,14 @@
 use std::borrow::Borrow;
-use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::rc::Rc;
 
 use thiserror::Error;
 
 use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, SlottedPage};
-use crate::buffer_manager::PageBuffer;
+use crate::buffer_manager::PageReadGuard;
 use crate::disk_manager::PageId;
 
 pub struct Node {
-    page_buffer: Rc<PageBuffer>,
+    page: PageReadGuard,
 }
 
 // Deleteの実装
@@ -23,9 +21,9 @@ pub struct Node {
 // 並行処理化する
 
 impl Node {
-    pub fn new(page_buffer: Rc<PageBuffer>) -> Self {
+    pub fn new(page: PageReadGuard) -> Self {
         Self {
-            page_buffer
+            page
         }
     }
 
@@ -36,12 +34,12 @@ impl Node {
     // }
 
     pub fn is_leaf(&self) -> bool {
-        let magic_number = self.page_buffer.page.borrow().header_view().magic_number().read();
+        let magic_number = self.page.page().header_view().magic_number().read();
         return magic_number == MAGIC_NUMBER_LEAF;
     }
 
     pub fn find(&self, key: &[u8]) -> (u16, bool) {
-        let page_ref = self.page_buffer.page.borrow();
+        let page_ref = self.page.page();
         let header_view = page_ref.header_view();
         let number_of_pointers = header_view.number_of_pointers().read();
 
@@ -82,7 +80,8 @@ mod tests {
     use std::borrow::BorrowMut;
     use std::io::Write;
 
-    use crate::btree::slotted_page::{cell, MAGIC_NUMBER_LEAF, pointer};
+    use crate::btree::slotted_page::{cell, MAGIC_NUMBER_LEAF, PAGE_SIZE, pointer};
+    use crate::buffer_manager::{BufferManager, Lookup};
 
     use super::*;
 
@@ -90,16 +89,15 @@ mod tests {
     fn test_find() {
         let number_of_cells: usize = 5;
         let cell_size: usize = 8;
-        let mut page = SlottedPage::new(MAGIC_NUMBER_LEAF);
+        let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
         for i in 1..=number_of_cells {
             let key = ((i * 2) as u16).to_be_bytes();
             let value = (0xffff as u16).to_be_bytes();
             page.add_cell((i - 1) as usize, &key, &value).unwrap();
         }
-        let node = Node::new(Rc::new(PageBuffer {
-            page: RefCell::new(page),
-            is_dirty: false,
-        }));
+        let buffer_manager = BufferManager::new(1, PAGE_SIZE).unwrap();
+        let Lookup::Miss(load) = buffer_manager.lookup(PageId(1)) else { panic!() };
+        let node = Node::new(load.finish(page, |_, _| Ok(())).unwrap());
         assert_eq!(node.find(&(2 as u16).to_be_bytes()), (0, true));
         assert_eq!(node.find(&(3 as u16).to_be_bytes()), (1, false));
         assert_eq!(node.find(&(9 as u16).to_be_bytes()), (4, false));
@@ -1,14 +1,19 @@
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
 use std::borrow::{Borrow, BorrowMut};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
 
 use anyhow::Result;
-use binary_layout::define_layout;
+use binary_layout::{define_layout, Field};
 use binary_layout::FieldSliceAccess;
 use thiserror::Error;
 
 use crate::btree::slotted_page::cell::body;
+use crate::disk_manager::DiskError;
 
 /*
- 4KiB per a page
+ 4KiB to 32KiB per a page, chosen when a database is created
  -------------------------------------------------------------------
  |                        MagicNumber(4b)                          |
  -------------------------------------------------------------------
@@ -18,22 +23,41 @@ use crate::btree::slotted_page::cell::body;
  -------------------------------------------------------------------
  |                        Check sum (4b)                           |
  -------------------------------------------------------------------
+ |                         Page id (4b)                            |
+ -------------------------------------------------------------------
  |  Version (1b)  |                  Padding                       |
  -------------------------------------------------------------------
  |                          Pointers                               |
  -------------------------------------------------------------------
  |                           Cells                                 |
  -------------------------------------------------------------------
+ |                     Reserved (32b)                              |
+ -------------------------------------------------------------------
+ The reserved bytes at the end are left to the storage layer, e.g. for the tag of an encrypted page,
+ and they are not covered by the check sum.
  */
 
-// 4KiB
+// 4KiB, the default page size
 pub const PAGE_SIZE: usize = 1024 * 4;
-pub const HEADER_SIZE: usize = 20;
+pub const PAGE_SIZES: [usize; 4] = [PAGE_SIZE, PAGE_SIZE * 2, PAGE_SIZE * 4, PAGE_SIZE * 8];
+pub const MAX_PAGE_SIZE: usize = PAGE_SIZE * 8;
+// Pages are aligned to this in memory so that they can be handed to O_DIRECT I/O as is
+pub const PAGE_ALIGNMENT: usize = 4096;
+pub const HEADER_SIZE: usize = 24;
+pub const RESERVED_SIZE: usize = 32;
+// The header of a V1 page had neither the page id nor the reserved bytes, and V1 pages are not readable any more
 pub const PAGE_VERSION_V1: u8 = 1;
+pub const PAGE_VERSION_V2: u8 = 2;
 pub const POINTER_SIZE: usize = 32;
 // 0x32DD is a prefix which represents a page
 pub const MAGIC_NUMBER_LEAF: u32 = 0x32DD56AA;
 pub const MAGIC_NUMBER_INTERNAL: u32 = 0x32DD77AB;
+// The reserved meta page holding the file header
+pub const MAGIC_NUMBER_META: u32 = 0x32DD11AC;
+// A trunk page of the free page list
+pub const MAGIC_NUMBER_FREE_LIST: u32 = 0x32DD33AD;
+// The directory page of the double-write area
+pub const MAGIC_NUMBER_DOUBLE_WRITE: u32 = 0x32DD44AE;
 
 define_layout!(page_header, BigEndian, {
     magic_number: u32,
@@ -41,12 +65,15 @@ define_layout!(page_header, BigEndian, {
     cell_offset: u16,
     next_overflow_page_id: u32,
     check_sum: u32,
+    // Stamped by DiskManager on write, to detect a page written to a wrong place
+    page_id: u32,
     version: u8,
 });
 
+// The body is followed by the reserved bytes
 define_layout!(page, BigEndian, {
     header: page_header::NestedView,
-    body: [u8; PAGE_SIZE - HEADER_SIZE],
+    body: [u8],
 });
 
 define_layout!(pointer, BigEndian, {
@@ -60,9 +87,75 @@ define_layout!(cell, BigEndian, {
     body: [u8],
 });
 
-#[derive(Debug)]
+pub fn is_valid_page_size(page_size: usize) -> bool {
+    PAGE_SIZES.contains(&page_size)
+}
+
+// The size of the body of a page, where pointers and cells live
+pub fn body_size(page_size: usize) -> usize {
+    page_size - HEADER_SIZE - RESERVED_SIZE
+}
+
+// Zeroed bytes on the heap aligned to PAGE_ALIGNMENT
+struct PageBytes {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// PageBytes owns its memory exclusively like a Box
+unsafe impl Send for PageBytes {}
+unsafe impl Sync for PageBytes {}
+
+impl PageBytes {
+    fn zeroed(len: usize) -> Self {
+        let layout = Self::layout(len);
+        let ptr = unsafe { alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout));
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, PAGE_ALIGNMENT).unwrap()
+    }
+}
+
+impl Deref for PageBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for PageBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Clone for PageBytes {
+    fn clone(&self) -> Self {
+        let mut bytes = Self::zeroed(self.len);
+        bytes.copy_from_slice(self);
+        bytes
+    }
+}
+
+impl Drop for PageBytes {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+impl fmt::Debug for PageBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PageBytes({} bytes)", self.len)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct SlottedPage {
-    data: [u8; PAGE_SIZE],
+    data: PageBytes,
 }
 
 #[derive(Debug, Error)]
@@ -74,56 +167,78 @@ pub enum Error {
 }
 
 impl SlottedPage {
-    pub fn new(magic_number: u32) -> Self {
-        let mut s = Self {
-            data: [0; PAGE_SIZE]
-        };
+    // Fails with UnsupportedPageSize unless the page size is one of PAGE_SIZES
+    pub fn new(page_size: usize, magic_number: u32) -> Result<Self, DiskError> {
+        let mut s = Self::zeroed(page_size)?;
         s.header_view_mut().magic_number_mut().write(magic_number);
-        s.header_view_mut().version_mut().write(PAGE_VERSION_V1);
-        let offset = (PAGE_SIZE - HEADER_SIZE) as u16;
+        s.header_view_mut().version_mut().write(PAGE_VERSION_V2);
+        let offset = body_size(page_size) as u16;
         s.header_view_mut().cell_offset_mut().write(offset);
 
         let sum = s.check_sum();
         s.header_view_mut().check_sum_mut().write(sum);
 
-        s
+        Ok(s)
     }
 
-    pub fn wrap(data: [u8; PAGE_SIZE]) -> Self {
-        Self {
-            data
+    // A page to read into
+    pub fn zeroed(page_size: usize) -> Result<Self, DiskError> {
+        if !is_valid_page_size(page_size) {
+            return Err(DiskError::UnsupportedPageSize(page_size));
         }
+        Ok(Self {
+            data: PageBytes::zeroed(page_size)
+        })
     }
 
-    pub fn valid(&mut self) -> bool {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DiskError> {
+        let mut s = Self::zeroed(bytes.len())?;
+        s.data.copy_from_slice(bytes);
+        Ok(s)
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn valid(&self) -> bool {
         let m = self.header_view().magic_number().read();
         if m != MAGIC_NUMBER_INTERNAL && m != MAGIC_NUMBER_LEAF {
             return false;
         }
+        return self.verify_check_sum();
+    }
+
+    // Regardless of the page type, every page keeps its check sum in the header
+    pub fn verify_check_sum(&self) -> bool {
         let check_sum = self.check_sum();
-        return self.header_view().check_sum().read() == check_sum;
+        self.header_view().check_sum().read() == check_sum
     }
 
     pub fn empty(&self) -> bool {
         return self.header_view().number_of_pointers().read() <= 0;
     }
 
-    pub fn to_bytes(&self) -> &[u8; PAGE_SIZE] {
-        page::View::new(&self.data).into_storage()
+    pub fn to_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn to_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data
     }
 
     // Postgres
     // https://github.com/postgres/postgres/blob/2cd2569c72b8920048e35c31c9be30a6170e1410/src/include/storage/checksum_impl.h#L196
-    pub fn check_sum(&mut self) -> u32 {
-        let old_csum = self.header_view().check_sum().read();
-
+    pub fn check_sum(&self) -> u32 {
         // Derived from PostgresSQL implementation
         // https://github.com/postgres/postgres/blob/2cd2569c72b8920048e35c31c9be30a6170e1410/src/include/storage/checksum_impl.h#L196
-        self.header_view_mut().check_sum_mut().write(0);
-        let csum = crc32fast::hash(&self.data);
-        self.header_view_mut().check_sum_mut().write(old_csum);
-
-        csum
+        // The check sum field itself is hashed as 0, so that a page can be verified through a shared reference
+        let offset = page_header::check_sum::OFFSET;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.data[..offset]);
+        hasher.update(&[0; 4]);
+        hasher.update(&self.data[offset + 4..self.data.len() - RESERVED_SIZE]);
+        hasher.finalize()
     }
 
     pub fn header_view(&self) -> page_header::View<impl AsRef<[u8]> + '_> {
@@ -137,11 +252,13 @@ impl SlottedPage {
     }
 
     pub fn body_view(&self) -> &[u8] {
-        page::body::data(&self.data[..])
+        let end = body_size(self.page_size());
+        &page::body::data(&self.data[..])[..end]
     }
 
     pub fn body_view_mut(&mut self) -> &mut [u8] {
-        page::body::data_mut(&mut self.data[..])
+        let end = body_size(self.page_size());
+        &mut page::body::data_mut(&mut self.data[..])[..end]
     }
 
     pub fn pointer_view(&self, index: usize) -> pointer::View<impl AsRef<[u8]> + '_> {
@@ -237,18 +354,48 @@ mod tests {
 
     #[test]
     fn test_new() {
-        let page = SlottedPage::new(MAGIC_NUMBER_LEAF);
+        let page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
         assert_eq!(page.header_view().magic_number().read(), MAGIC_NUMBER_LEAF);
-        assert_eq!(page.header_view().version().read(), PAGE_VERSION_V1);
-        assert_eq!(page.header_view().check_sum().read(), 2327031672);
+        assert_eq!(page.header_view().version().read(), PAGE_VERSION_V2);
+        // Changes only with the page layout, and then PAGE_VERSION is bumped as well
+        assert_eq!(page.header_view().check_sum().read(), 479567454);
         assert_eq!(page.header_view().next_overflow_page_id().read(), 0);
         assert_eq!(page.header_view().number_of_pointers().read(), 0);
-        assert_eq!(page.header_view().cell_offset().read(), (PAGE_SIZE - HEADER_SIZE) as u16);
+        assert_eq!(page.header_view().cell_offset().read(), body_size(PAGE_SIZE) as u16);
+    }
+
+    #[test]
+    fn test_alignment() {
+        for page_size in PAGE_SIZES {
+            let page = SlottedPage::new(page_size, MAGIC_NUMBER_LEAF).unwrap();
+            assert_eq!(page.to_bytes().as_ptr() as usize % PAGE_ALIGNMENT, 0);
+            assert_eq!(page.page_size(), page_size);
+        }
+    }
+
+    #[test]
+    fn test_page_sizes() {
+        for page_size in PAGE_SIZES {
+            let mut page = SlottedPage::new(page_size, MAGIC_NUMBER_LEAF).unwrap();
+            assert_eq!(page.header_view().cell_offset().read() as usize, body_size(page_size));
+            let mut number_of_cells = 0;
+            while page.add_cell(number_of_cells, &(number_of_cells as u32).to_be_bytes(), &[0xab; 100]).is_ok() {
+                number_of_cells += 1;
+            }
+            // Each cell takes a pointer and 4 bytes of lengths besides the key and the value
+            assert_eq!(number_of_cells, body_size(page_size) / (pointer::SIZE.unwrap() + 4 + 4 + 100));
+            assert_eq!(page.valid(), true);
+            let last = number_of_cells - 1;
+            assert_eq!(page.cell_view(last).body()[..4], (last as u32).to_be_bytes());
+        }
+        assert_eq!(matches!(SlottedPage::zeroed(PAGE_SIZE + 1), Err(DiskError::UnsupportedPageSize(_))), true);
+        assert_eq!(matches!(SlottedPage::new(PAGE_SIZE + 1, MAGIC_NUMBER_LEAF), Err(DiskError::UnsupportedPageSize(_))), true);
+        assert_eq!(matches!(SlottedPage::from_bytes(&[0; 100]), Err(DiskError::UnsupportedPageSize(100))), true);
     }
 
     #[test]
     fn test_get_pointer() {
-        let mut page = SlottedPage::new(MAGIC_NUMBER_LEAF);
+        let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
         page.header_view_mut().number_of_pointers_mut().write(2);
 
         let mut bytes: [u8; 4] = [0; 4];
@@ -269,7 +416,7 @@ mod tests {
     fn test_add_cell() {
         let cell_size: usize = 8;
         let number_of_cells: usize = 5;
-        let mut page = SlottedPage::new(MAGIC_NUMBER_LEAF);
+        let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
         for i in 0..number_of_cells {
             let key = ((i + 1) as u16).to_be_bytes();
             let value = ((i * 2) as u16).to_be_bytes();
@@ -277,9 +424,9 @@ mod tests {
         }
         assert_eq!(page.header_view().magic_number().read(), MAGIC_NUMBER_LEAF);
         assert_eq!(page.header_view().number_of_pointers().read(), number_of_cells as u16);
-        assert_eq!(page.header_view().cell_offset().read(), (PAGE_SIZE - HEADER_SIZE - number_of_cells * cell_size) as u16);
+        assert_eq!(page.header_view().cell_offset().read(), (body_size(PAGE_SIZE) - number_of_cells * cell_size) as u16);
         for i in 0..number_of_cells {
-            assert_eq!(page.pointer_view(i).cell_offset().read(), (PAGE_SIZE - HEADER_SIZE - cell_size * (i + 1)) as u16);
+            assert_eq!(page.pointer_view(i).cell_offset().read(), (body_size(PAGE_SIZE) - cell_size * (i + 1)) as u16);
             assert_eq!(page.pointer_view(i).cell_length().read(), 8);
             assert_eq!(page.cell_view(i).key_length().read(), 2);
             assert_eq!(page.cell_view(i).value_length().read(), 2);
@@ -295,7 +442,7 @@ mod tests {
         // Check if the new entry is inserted into the head
         page.add_cell(0, &(0 as u16).to_be_bytes(), &(2048 as u16).to_be_bytes()).unwrap();
         assert_eq!(page.header_view().number_of_pointers().read(), (number_of_cells + 1) as u16);
-        assert_eq!(page.header_view().cell_offset().read(), (PAGE_SIZE - HEADER_SIZE - (number_of_cells + 1) * cell_size) as u16);
+        assert_eq!(page.header_view().cell_offset().read(), (body_size(PAGE_SIZE) - (number_of_cells + 1) * cell_size) as u16);
         assert_eq!(page.pointer_view(0).cell_offset().read(), next_cell_offset - cell_size as u16);
         assert_eq!(page.pointer_view(0).cell_length().read(), 8);
         let key = (0 as u16).to_be_bytes();
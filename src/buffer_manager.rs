@@ -1,9 +1,18 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 use thiserror::Error;
 
 use crate::btree::slotted_page::SlottedPage;
+use crate::buffer_manager::access_strategy::{AccessStrategy, StrategyKind};
+use crate::buffer_manager::page_table::{Entry, PageTable};
+use crate::buffer_manager::replacement_policy::{Policy, ReplacementPolicy};
+use crate::disk_manager::{DiskError, PageId};
+
+pub mod access_strategy;
+pub mod page_table;
+pub mod replacement_policy;
 
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct BufferId(pub u32);
@@ -25,162 +34,936 @@ impl BufferId {
 pub enum BufferError {
     #[error("no free buffer available in buffer pool")]
     NoFreeBuffer,
+    #[error("failed to write back the evicted page {0:?}")]
+    WriteBack(PageId, #[source] anyhow::Error),
+    #[error(transparent)]
+    Disk(#[from] DiskError),
+    // e.g. LRU-K with K of 0
+    #[error("invalid replacement policy: {0:?}")]
+    InvalidPolicy(Policy),
 }
 
+// A frame of the buffer pool. Frames are allocated once, and a page is swapped into a frame
+// only while the frame is pinned by the evicting thread alone.
 #[derive(Debug)]
 pub struct PageBuffer {
-    pub is_dirty: bool,
-    pub page: RefCell<SlottedPage>,
+    // PageId(0) while the frame is unused, since it never points to a regular page
+    page_id: AtomicU32,
+    // Set by mutable access, and cleared once the page is written back
+    is_dirty: AtomicBool,
+    // The number of pins alive, and the frame is never evicted while it is pinned
+    pin_count: AtomicU32,
+    // The latch of the frame
+    page: RwLock<SlottedPage>,
+}
+
+impl PageBuffer {
+    fn empty(page_size: usize) -> Result<Self, DiskError> {
+        Ok(Self {
+            page_id: AtomicU32::new(0),
+            is_dirty: AtomicBool::new(false),
+            pin_count: AtomicU32::new(0),
+            page: RwLock::new(SlottedPage::zeroed(page_size)?),
+        })
+    }
+
+    pub fn page_id(&self) -> PageId {
+        PageId(self.page_id.load(Ordering::Acquire))
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.is_dirty.load(Ordering::Acquire)
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pin_count.load(Ordering::Acquire) > 0
+    }
+
+    fn unpin(&self) {
+        self.pin_count.fetch_sub(1, Ordering::Release);
+    }
+
+    // A panic while the latch is held leaves the page in an unknown state, so it is propagated
+    fn read(&self) -> RwLockReadGuard<'_, SlottedPage> {
+        self.page.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, SlottedPage> {
+        self.page.write().unwrap()
+    }
+
+    // None when another thread holds the exclusive latch
+    fn try_read(&self) -> Option<RwLockReadGuard<'_, SlottedPage>> {
+        match self.page.try_read() {
+            Ok(page) => Some(page),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(err)) => panic!("{}", err),
+        }
+    }
+}
+
+// Keeps the frame pinned while it is alive, without latching it
+#[derive(Debug)]
+struct FramePin {
+    buffer_id: BufferId,
+    buffer: Arc<PageBuffer>,
+}
+
+impl FramePin {
+    // Must not be called while the shard of the page is locked, since the holder of the latch
+    // may be waiting for the shard
+    fn read(self) -> PageReadGuard {
+        // The frame outlives the latch, since the guard keeps the frame alive through the pin and
+        // releases the latch before the pin
+        let page = unsafe {
+            std::mem::transmute::<RwLockReadGuard<'_, SlottedPage>, RwLockReadGuard<'static, SlottedPage>>(self.buffer.read())
+        };
+        PageReadGuard { page, pin: self }
+    }
+
+    fn write(self) -> PageWriteGuard {
+        let page = unsafe {
+            std::mem::transmute::<RwLockWriteGuard<'_, SlottedPage>, RwLockWriteGuard<'static, SlottedPage>>(self.buffer.write())
+        };
+        PageWriteGuard { page, pin: self }
+    }
+}
+
+impl Drop for FramePin {
+    fn drop(&mut self) {
+        self.buffer.unpin();
+    }
+}
+
+// Keeps the frame pinned and holds its shared latch while it is alive
+#[derive(Debug)]
+pub struct PageReadGuard {
+    // Declared before the pin, so that the latch is released before the frame is unpinned
+    page: RwLockReadGuard<'static, SlottedPage>,
+    pin: FramePin,
+}
+
+impl PageReadGuard {
+    pub fn buffer_id(&self) -> BufferId {
+        self.pin.buffer_id
+    }
+
+    pub fn page_id(&self) -> PageId {
+        self.pin.buffer.page_id()
+    }
+
+    pub fn page(&self) -> &SlottedPage {
+        &self.page
+    }
+
+    // The shared latch is released before the exclusive latch is taken, and the frame stays pinned in between
+    pub fn into_write(self) -> PageWriteGuard {
+        let PageReadGuard { page, pin } = self;
+        drop(page);
+        pin.write()
+    }
+}
+
+// Keeps the frame pinned and holds its exclusive latch while it is alive
+#[derive(Debug)]
+pub struct PageWriteGuard {
+    // Declared before the pin, so that the latch is released before the frame is unpinned
+    page: RwLockWriteGuard<'static, SlottedPage>,
+    pin: FramePin,
+}
+
+impl PageWriteGuard {
+    pub fn buffer_id(&self) -> BufferId {
+        self.pin.buffer_id
+    }
+
+    pub fn page_id(&self) -> PageId {
+        self.pin.buffer.page_id()
+    }
+
+    pub fn page(&self) -> &SlottedPage {
+        &self.page
+    }
+
+    // The frame is marked dirty while the latch is held, so that a concurrent flush either writes
+    // the modification or leaves the frame dirty
+    pub fn page_mut(&mut self) -> &mut SlottedPage {
+        self.pin.buffer.is_dirty.store(true, Ordering::Release);
+        &mut self.page
+    }
 }
 
-impl Default for PageBuffer {
-    fn default() -> Self {
-        Self {
-            is_dirty: false,
-            page: RefCell::new(SlottedPage::new(0)),
+pub enum Lookup<'a> {
+    Hit(PageReadGuard),
+    // The page is not in the pool, and the caller is expected to read it from the disk
+    Miss(PageLoad<'a>),
+    // Another thread is reading the page from the disk
+    Loading,
+}
+
+// Reserves the page in the page table while it is read from the disk.
+// Dropping it without finish() gives the reservation up, e.g. when the read failed.
+pub struct PageLoad<'a> {
+    manager: &'a BufferManager,
+    page_id: PageId,
+    finished: bool,
+}
+
+impl PageLoad<'_> {
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    // Puts the page into a frame, and evicts another page when the pool is full.
+    // A dirty victim is handed to write_back before its frame is reused.
+    pub fn finish<F>(self, page: SlottedPage, write_back: F) -> Result<PageReadGuard, BufferError>
+    where
+        F: FnMut(PageId, &SlottedPage) -> anyhow::Result<()>,
+    {
+        self.load(page, None, write_back)
+    }
+
+    // Same as finish(), but the page goes into the ring of the strategy
+    pub fn finish_with_strategy<F>(self, page: SlottedPage, strategy: &mut AccessStrategy, write_back: F) -> Result<PageReadGuard, BufferError>
+    where
+        F: FnMut(PageId, &SlottedPage) -> anyhow::Result<()>,
+    {
+        self.load(page, Some(strategy), write_back)
+    }
+
+    fn load<F>(mut self, page: SlottedPage, mut strategy: Option<&mut AccessStrategy>, write_back: F) -> Result<PageReadGuard, BufferError>
+    where
+        F: FnMut(PageId, &SlottedPage) -> anyhow::Result<()>,
+    {
+        let manager = self.manager;
+        if page.page_size() != manager.page_size {
+            return Err(DiskError::PageSizeMismatch(manager.page_size as u32).into());
         }
+        let buffer_id = manager.evict(self.page_id, strategy.as_deref_mut(), write_back)?;
+        if let Some(strategy) = strategy {
+            strategy.record_load(buffer_id, self.page_id);
+        }
+        let buffer = &manager.frames[buffer_id.to_usize()];
+        *buffer.write() = page;
+        buffer.page_id.store(self.page_id.to_u32(), Ordering::Release);
+        manager.policy.record_insert(buffer_id, self.page_id);
+
+        manager.page_table.lock(self.page_id).insert(self.page_id, Entry::Loaded(buffer_id));
+        manager.page_table.notify(self.page_id);
+        self.finished = true;
+        // The pin taken by the eviction is handed over to the guard
+        Ok(FramePin { buffer_id, buffer: Arc::clone(buffer) }.read())
     }
 }
 
-#[derive(Debug, Default)]
-pub struct BufferItem {
-    is_pinned: bool,
-    usage_count: u32,
-    buffer: Rc<PageBuffer>,
+impl Drop for PageLoad<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.manager.page_table.lock(self.page_id).remove(&self.page_id);
+        self.manager.page_table.notify(self.page_id);
+    }
 }
 
 pub struct BufferManager {
-    // Clock-sweep algorithm
-    cache: Vec<BufferItem>,
-    buffer_cursor: BufferId,
+    frames: Vec<Arc<PageBuffer>>,
+    // Frames which have never held a page, used up before the policy is asked for a victim
+    free_buffers: Mutex<Vec<BufferId>>,
+    policy: Box<dyn ReplacementPolicy>,
+    page_table: PageTable,
+    page_size: usize,
+    // The pages being written back, from before they are marked clean until the write returns
+    write_backs: Mutex<HashMap<PageId, usize>>,
+    written_back: Condvar,
+}
+
+// Registers pages as being written back while it is alive
+struct WriteBack<'a> {
+    manager: &'a BufferManager,
+    page_ids: Vec<PageId>,
+}
+
+impl Drop for WriteBack<'_> {
+    fn drop(&mut self) {
+        let mut write_backs = self.manager.write_backs.lock().unwrap();
+        for page_id in self.page_ids.iter() {
+            let count = write_backs.get_mut(page_id).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                write_backs.remove(page_id);
+            }
+        }
+        self.manager.written_back.notify_all();
+    }
 }
 
 impl BufferManager {
-    pub fn new(size: usize) -> Self {
-        let cursor = BufferId(0);
-        let mut cache = vec![];
-        cache.resize_with(size, Default::default);
-        Self {
-            cache,
-            buffer_cursor: cursor,
+    pub fn new(size: usize, page_size: usize) -> Result<Self, BufferError> {
+        Self::with_policy(size, page_size, Policy::default())
+    }
+
+    pub fn with_policy(size: usize, page_size: usize, policy: Policy) -> Result<Self, BufferError> {
+        let frames = (0..size).map(|_| PageBuffer::empty(page_size).map(Arc::new)).collect::<Result<_, _>>()?;
+        Ok(Self {
+            frames,
+            free_buffers: Mutex::new((0..size as u32).rev().map(BufferId).collect()),
+            policy: policy.build(size)?,
+            page_table: PageTable::new(),
+            page_size,
+            write_backs: Mutex::new(HashMap::new()),
+            written_back: Condvar::new(),
+        })
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    // Waits for another thread reading the page, and reserves the page for the caller to read
+    // when it is not in the pool
+    pub fn lookup(&self, page_id: PageId) -> Lookup<'_> {
+        self.find(page_id, true, true)
+    }
+
+    // Same as lookup(), but returns Lookup::Loading instead of waiting, for a caller which
+    // has reserved other pages and would deadlock with a thread waiting for them
+    pub fn try_lookup(&self, page_id: PageId) -> Lookup<'_> {
+        self.find(page_id, false, true)
+    }
+
+    pub fn fetch_page(&self, page_id: PageId) -> Option<PageReadGuard> {
+        match self.find(page_id, true, false) {
+            Lookup::Hit(guard) => Some(guard),
+            _ => None,
         }
     }
 
-    // TODO: implement concurrency control later
-    pub fn add_page(&mut self, item: SlottedPage) -> Result<BufferId, BufferError> {
-        let mut pinned_count = 0;
+    pub fn fetch_page_mut(&self, page_id: PageId) -> Option<PageWriteGuard> {
+        Some(self.fetch_page(page_id)?.into_write())
+    }
+
+    fn find(&self, page_id: PageId, wait: bool, reserve: bool) -> Lookup<'_> {
+        let mut entries = self.page_table.lock(page_id);
         loop {
-            if pinned_count >= self.cache.len() {
-                return Err(BufferError::NoFreeBuffer);
+            match entries.get(&page_id) {
+                Some(&Entry::Loaded(buffer_id)) => {
+                    // The mapping is removed under the same lock before the frame is reused
+                    debug_assert_eq!(self.frames[buffer_id.to_usize()].page_id(), page_id);
+                    self.policy.record_access(buffer_id);
+                    let pin = self.pin(buffer_id);
+                    drop(entries);
+                    return Lookup::Hit(pin.read());
+                }
+                Some(Entry::Loading) if wait => entries = self.page_table.wait(page_id, entries),
+                Some(Entry::Loading) => return Lookup::Loading,
+                None if reserve => {
+                    entries.insert(page_id, Entry::Loading);
+                    return Lookup::Miss(PageLoad { manager: self, page_id, finished: false });
+                }
+                None => return Lookup::Loading,
             }
-            let item = &mut self.cache[self.buffer_cursor.to_usize()];
-            if item.is_pinned {
-                pinned_count += 1;
-                self.increment_next_buffer_id();
-                continue;
+        }
+    }
+
+    // Must be called with the shard of the page locked
+    fn pin(&self, buffer_id: BufferId) -> FramePin {
+        let buffer = &self.frames[buffer_id.to_usize()];
+        buffer.pin_count.fetch_add(1, Ordering::Acquire);
+        FramePin { buffer_id, buffer: Arc::clone(buffer) }
+    }
+
+    // Returns an unmapped frame pinned by the caller alone, to load the page into.
+    // The caller has reserved the page being loaded, so the victim is never waited for: the thread
+    // holding its latch may be waiting for the reservation.
+    fn evict<F>(&self, page_id: PageId, mut strategy: Option<&mut AccessStrategy>, mut write_back: F) -> Result<BufferId, BufferError>
+    where
+        F: FnMut(PageId, &SlottedPage) -> anyhow::Result<()>,
+    {
+        loop {
+            let buffer_id = self.claim_victim(page_id, strategy.as_deref_mut())?;
+            let buffer = &self.frames[buffer_id.to_usize()];
+            if buffer.is_dirty() {
+                // Pinned and latched through the page table after it was claimed, so it is in use
+                let Some(page) = buffer.try_read() else {
+                    buffer.unpin();
+                    continue;
+                };
+                let page_id = buffer.page_id();
+                let in_progress = self.start_write_back(vec![page_id]);
+                buffer.is_dirty.store(false, Ordering::Release);
+                let ret = write_back(page_id, &page);
+                drop(page);
+                if let Err(err) = ret {
+                    buffer.is_dirty.store(true, Ordering::Release);
+                    buffer.unpin();
+                    return Err(BufferError::WriteBack(page_id, err));
+                }
+                drop(in_progress);
             }
-            // TODO: CAS
-            if item.usage_count > 0 {
-                item.usage_count -= 1;
-                self.increment_next_buffer_id();
-                continue;
+            let evicted = buffer.page_id();
+            if evicted.is_valid() {
+                let mut entries = self.page_table.lock(evicted);
+                // Pinned through the page table while the page was written back
+                if buffer.pin_count.load(Ordering::Acquire) > 1 || buffer.is_dirty() {
+                    drop(entries);
+                    buffer.unpin();
+                    continue;
+                }
+                entries.remove(&evicted);
+                self.policy.record_evict(buffer_id, evicted);
+            }
+            buffer.page_id.store(0, Ordering::Release);
+
+            return Ok(buffer_id);
+        }
+    }
+
+    // Pins the next frame of the ring, a free frame, or the victim chosen by the policy in this order.
+    // The victim may be pinned by another thread before it is claimed, and then the policy is asked again.
+    fn claim_victim(&self, page_id: PageId, strategy: Option<&mut AccessStrategy>) -> Result<BufferId, BufferError> {
+        if let Some(buffer_id) = strategy.and_then(|strategy| self.claim_from_ring(strategy)) {
+            return Ok(buffer_id);
+        }
+        if let Some(buffer_id) = self.free_buffers.lock().unwrap().pop() {
+            self.frames[buffer_id.to_usize()].pin_count.fetch_add(1, Ordering::Acquire);
+            return Ok(buffer_id);
+        }
+        let evictable = |buffer_id: BufferId| !self.frames[buffer_id.to_usize()].is_pinned();
+        loop {
+            let buffer_id = self.policy.victim(page_id, &evictable).ok_or(BufferError::NoFreeBuffer)?;
+            let buffer = &self.frames[buffer_id.to_usize()];
+            if buffer.pin_count.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                return Ok(buffer_id);
             }
-            break;
         }
-        let buffer_id = self.buffer_cursor;
-        let page_buffer = PageBuffer {
-            is_dirty: false,
-            page: RefCell::new(item),
-        };
-        let buffer_item = BufferItem {
-            usage_count: 0,
-            is_pinned: false,
-            buffer: Rc::new(page_buffer),
-        };
-        self.cache[buffer_id.to_usize()] = buffer_item;
-        self.increment_next_buffer_id();
-        Ok(buffer_id)
     }
 
-    pub fn fetch_page(&mut self, buffer_id: BufferId) -> Option<Rc<PageBuffer>> {
-        let index = buffer_id.to_usize();
-        if index >= self.cache.len() {
+    // The frame is reused only while it still holds the page loaded through the ring and nobody pins it
+    fn claim_from_ring(&self, strategy: &mut AccessStrategy) -> Option<BufferId> {
+        let (buffer_id, page_id) = strategy.next_slot()?;
+        let buffer = &self.frames[buffer_id.to_usize()];
+        if buffer.pin_count.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
             return None;
         }
-        let item = &mut self.cache[index];
-        let is_valid = item.buffer.page.borrow_mut().valid();
-        if !is_valid {
+        let rejected = strategy.kind() == StrategyKind::BulkRead && buffer.is_dirty();
+        if buffer.page_id() != page_id || rejected {
+            buffer.unpin();
             return None;
         }
-        item.usage_count += 1;
+        Some(buffer_id)
+    }
+
+    // A ring of PostgreSQL's size, but never more than an eighth of the pool
+    pub fn strategy(&self, kind: StrategyKind) -> AccessStrategy {
+        let ring_size = (kind.ring_bytes() / self.page_size).min(self.frames.len() / 8).max(1);
+        AccessStrategy::new(kind, ring_size)
+    }
+
+    // Pins every dirty frame, for a flush
+    fn dirty_pages(&self) -> Vec<FramePin> {
+        (0..self.frames.len() as u32).filter_map(|index| self.pin_dirty(BufferId(index))).collect()
+    }
+
+    pub fn dirty_page_ids(&self) -> Vec<PageId> {
+        self.frames.iter().filter(|buffer| buffer.is_dirty()).map(|buffer| buffer.page_id()).collect()
+    }
+
+    // Pins the frame if it is dirty, through the page table since it may have been evicted in the meantime
+    fn pin_dirty(&self, buffer_id: BufferId) -> Option<FramePin> {
+        let buffer = &self.frames[buffer_id.to_usize()];
+        if !buffer.is_dirty() {
+            return None;
+        }
+        let page_id = buffer.page_id();
+        let entries = self.page_table.lock(page_id);
+        match entries.get(&page_id) {
+            Some(&Entry::Loaded(loaded)) if loaded == buffer_id => Some(self.pin(buffer_id)),
+            _ => None,
+        }
+    }
+
+    // Writes every dirty page with a single call of write_pages
+    pub fn flush_all<F>(&self, write_pages: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&[(PageId, &SlottedPage)]) -> anyhow::Result<()>,
+    {
+        self.write_dirty(self.dirty_pages(), write_pages)?;
+        Ok(())
+    }
+
+    // Writes the pages still dirty in the pool among the given ones, and returns how many were written
+    pub fn flush_pages<F>(&self, page_ids: &[PageId], write_pages: F) -> anyhow::Result<usize>
+    where
+        F: FnOnce(&[(PageId, &SlottedPage)]) -> anyhow::Result<()>,
+    {
+        let pins = page_ids.iter()
+            .filter_map(|&page_id| match self.page_table.lock(page_id).get(&page_id) {
+                Some(&Entry::Loaded(buffer_id)) => Some(buffer_id),
+                _ => None,
+            })
+            .filter_map(|buffer_id| self.pin_dirty(buffer_id))
+            .collect();
+        self.write_dirty(pins, write_pages)
+    }
+
+    // Writes the dirty pages among the next max_pages frames the policy would evict, so that
+    // the eviction does not have to wait for the write. Frames in use are skipped, since they are
+    // likely to be dirtied again.
+    pub fn clean_ahead<F>(&self, max_pages: usize, write_pages: F) -> anyhow::Result<usize>
+    where
+        F: FnOnce(&[(PageId, &SlottedPage)]) -> anyhow::Result<()>,
+    {
+        let pins = self.policy.upcoming_victims(max_pages).into_iter()
+            .filter(|buffer_id| !self.frames[buffer_id.to_usize()].is_pinned())
+            .filter_map(|buffer_id| self.pin_dirty(buffer_id))
+            .collect();
+        self.write_dirty(pins, write_pages)
+    }
+
+    // Each page is copied and marked clean under its latch, which is released before the next one is taken,
+    // and the frames are marked dirty again when the write fails
+    fn write_dirty<F>(&self, pins: Vec<FramePin>, write_pages: F) -> anyhow::Result<usize>
+    where
+        F: FnOnce(&[(PageId, &SlottedPage)]) -> anyhow::Result<()>,
+    {
+        if pins.is_empty() {
+            return Ok(0);
+        }
+        let in_progress = self.start_write_back(pins.iter().map(|pin| pin.buffer.page_id()).collect());
+        let copies: Vec<SlottedPage> = pins.iter()
+            .map(|pin| {
+                let page = pin.buffer.read();
+                pin.buffer.is_dirty.store(false, Ordering::Release);
+                page.clone()
+            })
+            .collect();
+        let requests: Vec<(PageId, &SlottedPage)> = pins.iter().zip(copies.iter()).map(|(pin, page)| (pin.buffer.page_id(), page)).collect();
+        if let Err(err) = write_pages(&requests) {
+            for pin in pins.iter() {
+                pin.buffer.is_dirty.store(true, Ordering::Release);
+            }
+            return Err(err);
+        }
+        drop(in_progress);
 
-        Some(Rc::clone(&item.buffer))
+        Ok(pins.len())
     }
 
-    fn increment_next_buffer_id(&mut self) {
-        let next_id = ((self.buffer_cursor.to_usize() + 1) % self.cache.len()) as u32;
-        self.buffer_cursor = BufferId(next_id);
+    // Must be called before the pages are marked clean
+    fn start_write_back(&self, page_ids: Vec<PageId>) -> WriteBack<'_> {
+        let mut write_backs = self.write_backs.lock().unwrap();
+        for &page_id in page_ids.iter() {
+            *write_backs.entry(page_id).or_default() += 1;
+        }
+        WriteBack { manager: self, page_ids }
+    }
+
+    // Waits for the write-backs in progress, e.g. of evicted pages, and returns the pages they wrote.
+    // Every page marked clean before the call has been written then, unless its write failed and
+    // the page is dirty again.
+    pub fn wait_for_write_backs(&self) -> Vec<PageId> {
+        let mut write_backs = self.write_backs.lock().unwrap();
+        let page_ids: Vec<PageId> = write_backs.keys().copied().collect();
+        while page_ids.iter().any(|page_id| write_backs.contains_key(page_id)) {
+            write_backs = self.written_back.wait(write_backs).unwrap();
+        }
+        page_ids
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::btree::slotted_page::MAGIC_NUMBER_LEAF;
+    use std::sync::Barrier;
+    use std::thread;
+
+    use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, PAGE_SIZE};
+    use crate::test_util::{leaf_page, random_indices};
 
     use super::*;
 
-    #[test]
-    fn test_increment_next_buffer_id() {
-        let mut manager = BufferManager::new(2);
-        assert_eq!(manager.buffer_cursor.to_usize(), 0);
-        manager.increment_next_buffer_id();
-        assert_eq!(manager.buffer_cursor.to_usize(), 1);
-        manager.increment_next_buffer_id();
-        assert_eq!(manager.buffer_cursor.to_usize(), 0);
+    // Pages in these tests are never modified, so nothing is written back
+    fn clean(_: PageId, _: &SlottedPage) -> anyhow::Result<()> {
+        unreachable!()
+    }
+
+    fn add_page(manager: &BufferManager, page_id: PageId) -> Result<PageReadGuard, BufferError> {
+        match manager.lookup(page_id) {
+            Lookup::Miss(load) => load.finish(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap(), clean),
+            _ => panic!("the page {:?} is already in the pool", page_id),
+        }
     }
 
     #[test]
     fn test_add_page() {
-        let mut manager = BufferManager::new(2);
-        assert_eq!(manager.buffer_cursor.to_usize(), 0);
+        let manager = BufferManager::new(2, PAGE_SIZE).unwrap();
 
         // Add
-        let page = SlottedPage::new(MAGIC_NUMBER_LEAF);
-        let result = manager.add_page(page);
+        let result = add_page(&manager, PageId(1));
         assert_eq!(result.is_ok(), true);
-        assert_eq!(result.unwrap().to_usize(), 0);
-        assert_eq!(manager.buffer_cursor.to_usize(), 1);
+        assert_eq!(result.unwrap().buffer_id().to_usize(), 0);
 
         // Add over the capacity
-        let page = SlottedPage::new(MAGIC_NUMBER_LEAF);
-        let result = manager.add_page(page);
+        let result = add_page(&manager, PageId(2));
         assert_eq!(result.is_ok(), true);
-        assert_eq!(result.unwrap().to_usize(), 1);
-        assert_eq!(manager.buffer_cursor.to_usize(), 0);
+        assert_eq!(result.unwrap().buffer_id().to_usize(), 1);
 
-        let page = SlottedPage::new(MAGIC_NUMBER_LEAF);
-        let result = manager.add_page(page);
+        let result = add_page(&manager, PageId(3));
         assert_eq!(result.is_ok(), true);
-        assert_eq!(result.unwrap().to_usize(), 0);
-        assert_eq!(manager.buffer_cursor.to_usize(), 1);
+        assert_eq!(result.unwrap().buffer_id().to_usize(), 0);
+        // The evicted page is no longer in the page table
+        assert_eq!(manager.fetch_page(PageId(1)).is_none(), true);
+        assert_eq!(manager.page_table.len(), 2);
+
+        // A page of another size does not fit
+        let Lookup::Miss(load) = manager.lookup(PageId(4)) else { panic!() };
+        let result = load.finish(SlottedPage::new(PAGE_SIZE * 2, MAGIC_NUMBER_LEAF).unwrap(), |_, _| Ok(()));
+        assert_eq!(matches!(result, Err(BufferError::Disk(DiskError::PageSizeMismatch(_)))), true);
+        assert_eq!(manager.page_table.len(), 2);
+        assert_eq!(matches!(BufferManager::new(2, PAGE_SIZE + 1), Err(BufferError::Disk(DiskError::UnsupportedPageSize(_)))), true);
     }
 
     #[test]
     fn test_fetch_page() {
-        let buffer_id = BufferId(0);
-        let mut manager = BufferManager::new(2);
-        let ret = manager.fetch_page(buffer_id);
+        let manager = BufferManager::new(2, PAGE_SIZE).unwrap();
+        let ret = manager.fetch_page(PageId(1));
         assert_eq!(ret.is_none(), true);
 
-        let ret = manager.add_page(SlottedPage::new(MAGIC_NUMBER_LEAF));
+        let ret = add_page(&manager, PageId(1));
         assert_eq!(ret.is_ok(), true);
-        assert_eq!(ret.unwrap().to_usize(), 0);
+        assert_eq!(ret.unwrap().buffer_id().to_usize(), 0);
 
-        let ret = manager.fetch_page(buffer_id);
+        let ret = manager.fetch_page(PageId(1));
         assert_eq!(ret.is_some(), true);
         let p = ret.unwrap();
-        assert_eq!(p.page.borrow_mut().valid(), true);
-        assert_eq!(p.page.borrow().empty(), true);
-        assert_eq!(p.page.borrow().header_view().magic_number().read(), MAGIC_NUMBER_LEAF);
+        assert_eq!(p.page_id(), PageId(1));
+        assert_eq!(p.page().valid(), true);
+        assert_eq!(p.page().empty(), true);
+        assert_eq!(p.page().header_view().magic_number().read(), MAGIC_NUMBER_LEAF);
+    }
+
+    #[test]
+    fn test_pin() {
+        let manager = BufferManager::new(2, PAGE_SIZE).unwrap();
+        let read_guard = add_page(&manager, PageId(1)).unwrap();
+        drop(add_page(&manager, PageId(2)).unwrap());
+        let mut write_guard = manager.fetch_page_mut(PageId(2)).unwrap();
+        assert_eq!(read_guard.pin.buffer.is_pinned(), true);
+
+        // Every frame is pinned
+        let ret = add_page(&manager, PageId(3));
+        assert_eq!(matches!(ret, Err(BufferError::NoFreeBuffer)), true);
+        // The reservation is given up along with the failure
+        assert_eq!(manager.fetch_page(PageId(3)).is_none(), true);
+
+        // The frame is unpinned when the last guard is dropped
+        let another_guard = manager.fetch_page(PageId(1)).unwrap();
+        drop(read_guard);
+        let ret = add_page(&manager, PageId(3));
+        assert_eq!(matches!(ret, Err(BufferError::NoFreeBuffer)), true);
+        drop(another_guard);
+        write_guard.page_mut().add_cell(0, b"key", b"value").unwrap();
+        let ret = add_page(&manager, PageId(3));
+        assert_eq!(ret.unwrap().buffer_id(), BufferId(0));
+        assert_eq!(write_guard.page().cell_view(0).body(), b"keyvalue");
+    }
+
+    #[test]
+    fn test_latch() {
+        let manager = BufferManager::new(1, PAGE_SIZE).unwrap();
+        let Lookup::Miss(load) = manager.lookup(PageId(1)) else { panic!() };
+        drop(load.finish(leaf_page(Some((b"", &0_u64.to_be_bytes()))), clean).unwrap());
+
+        // The counter is read and written in separate steps, and the write guard excludes the other thread in between
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let mut guard = manager.fetch_page_mut(PageId(1)).unwrap();
+                        let counter = u64::from_be_bytes(guard.page().cell_view(0).body().try_into().unwrap());
+                        thread::yield_now();
+                        guard.page_mut().cell_view_mut(0).body_mut().copy_from_slice(&(counter + 1).to_be_bytes());
+                    }
+                });
+            }
+        });
+        let guard = manager.fetch_page(PageId(1)).unwrap();
+        assert_eq!(guard.page().cell_view(0).body(), 2000_u64.to_be_bytes());
+    }
+
+    #[test]
+    fn test_write_back() {
+        let manager = BufferManager::new(1, PAGE_SIZE).unwrap();
+        drop(add_page(&manager, PageId(1)).unwrap());
+        let mut guard = manager.fetch_page_mut(PageId(1)).unwrap();
+        assert_eq!(guard.pin.buffer.is_dirty(), false);
+        guard.page_mut().add_cell(0, b"key", b"value").unwrap();
+        assert_eq!(guard.pin.buffer.is_dirty(), true);
+        drop(guard);
+        assert_eq!(manager.dirty_pages().len(), 1);
+
+        // The frame is kept when the page cannot be written back
+        let Lookup::Miss(load) = manager.lookup(PageId(2)) else { panic!() };
+        let ret = load.finish(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap(), |_, _| Err(anyhow::anyhow!("no space")));
+        assert_eq!(matches!(ret, Err(BufferError::WriteBack(PageId(1), _))), true);
+        assert_eq!(manager.fetch_page(PageId(1)).unwrap().pin.buffer.is_dirty(), true);
+
+        let mut written = vec![];
+        let Lookup::Miss(load) = manager.lookup(PageId(2)) else { panic!() };
+        let ret = load.finish(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap(), |page_id, page| {
+            written.push((page_id, page.cell_view(0).body().to_vec()));
+            Ok(())
+        });
+        assert_eq!(ret.is_ok(), true);
+        assert_eq!(written, vec![(PageId(1), b"keyvalue".to_vec())]);
+        assert_eq!(manager.dirty_pages().len(), 0);
+    }
+
+    #[test]
+    fn test_wait_for_write_backs() {
+        let manager = BufferManager::new(1, PAGE_SIZE).unwrap();
+        drop(add_page(&manager, PageId(1)).unwrap());
+        manager.fetch_page_mut(PageId(1)).unwrap().page_mut().add_cell(0, b"key", b"value").unwrap();
+        assert_eq!(manager.wait_for_write_backs(), vec![]);
+
+        let started = Barrier::new(2);
+        let released = Barrier::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let Lookup::Miss(load) = manager.lookup(PageId(2)) else { panic!() };
+                load.finish(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap(), |_, _| {
+                    started.wait();
+                    released.wait();
+                    Ok(())
+                }).unwrap();
+            });
+            started.wait();
+            // The page is clean already, but its write has not landed yet
+            assert_eq!(manager.dirty_page_ids(), vec![]);
+            let waiter = s.spawn(|| manager.wait_for_write_backs());
+            thread::sleep(std::time::Duration::from_millis(50));
+            assert_eq!(waiter.is_finished(), false);
+            released.wait();
+            assert_eq!(waiter.join().unwrap(), vec![PageId(1)]);
+        });
+        assert_eq!(manager.wait_for_write_backs(), vec![]);
+    }
+
+    #[test]
+    fn test_flush_all() {
+        let manager = BufferManager::new(4, PAGE_SIZE).unwrap();
+        for i in 1..=4 {
+            drop(add_page(&manager, PageId(i)).unwrap());
+        }
+        for i in [2, 4] {
+            let mut guard = manager.fetch_page_mut(PageId(i)).unwrap();
+            guard.page_mut().add_cell(0, b"key", b"value").unwrap();
+        }
+
+        let ret = manager.flush_all(|_| Err(anyhow::anyhow!("no space")));
+        assert_eq!(ret.is_err(), true);
+        assert_eq!(manager.dirty_pages().len(), 2);
+
+        let mut written = vec![];
+        let ret = manager.flush_all(|pages| {
+            written.extend(pages.iter().map(|(page_id, _)| *page_id));
+            Ok(())
+        });
+        assert_eq!(ret.is_ok(), true);
+        assert_eq!(written, vec![PageId(2), PageId(4)]);
+        assert_eq!(manager.dirty_pages().len(), 0);
+
+        // The pages are written from copies, so they can be modified while the write is in progress
+        manager.fetch_page_mut(PageId(2)).unwrap().page_mut().add_cell(1, b"key2", b"value").unwrap();
+        let ret = manager.flush_all(|pages| {
+            assert_eq!(pages[0].1.header_view().number_of_pointers().read(), 2);
+            manager.fetch_page_mut(PageId(2)).unwrap().page_mut().add_cell(2, b"key3", b"value").unwrap();
+            Ok(())
+        });
+        assert_eq!(ret.is_ok(), true);
+        // Modified after the copy was taken, so the frame stays dirty
+        assert_eq!(manager.dirty_page_ids(), vec![PageId(2)]);
+    }
+
+    #[test]
+    fn test_clean_ahead() {
+        let manager = BufferManager::with_policy(4, PAGE_SIZE, Policy::Lru).unwrap();
+        let mut guards = vec![];
+        for i in 1..=4 {
+            let mut guard = add_page(&manager, PageId(i)).unwrap().into_write();
+            guard.page_mut().add_cell(0, b"key", b"value").unwrap();
+            guards.push(guard);
+        }
+        // Page 1 is the next victim, but it is in use
+        let guard = guards.remove(0);
+        drop(guards);
+        let mut written = vec![];
+        let ret = manager.clean_ahead(2, |pages| {
+            written.extend(pages.iter().map(|(page_id, _)| *page_id));
+            Ok(())
+        });
+        assert_eq!(ret.unwrap(), 1);
+        assert_eq!(written, vec![PageId(2)]);
+        drop(guard);
+
+        // Only the pages still dirty are written
+        let mut written = vec![];
+        let ret = manager.flush_pages(&[PageId(2), PageId(3), PageId(5)], |pages| {
+            written.extend(pages.iter().map(|(page_id, _)| *page_id));
+            Ok(())
+        });
+        assert_eq!(ret.unwrap(), 1);
+        assert_eq!(written, vec![PageId(3)]);
+        assert_eq!(manager.dirty_page_ids(), vec![PageId(1), PageId(4)]);
+    }
+
+    #[test]
+    fn test_access_strategy() {
+        let manager = BufferManager::new(8, PAGE_SIZE).unwrap();
+        for i in 1..=4 {
+            drop(add_page(&manager, PageId(i)).unwrap());
+        }
+
+        // The scan recycles a ring of 2 frames, and the other pages stay in the pool
+        let mut strategy = AccessStrategy::new(StrategyKind::BulkRead, 2);
+        let mut buffer_ids = vec![];
+        for i in 100..120 {
+            let Lookup::Miss(load) = manager.lookup(PageId(i)) else { panic!() };
+            let guard = load.finish_with_strategy(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap(), &mut strategy, clean).unwrap();
+            buffer_ids.push(guard.buffer_id());
+        }
+        assert_eq!(buffer_ids[2..], buffer_ids[..2].repeat(9));
+        for i in 1..=4 {
+            assert_eq!(manager.fetch_page(PageId(i)).is_some(), true);
+        }
+
+        // A scanned page dirtied by another thread is left to the pool
+        let mut guard = manager.fetch_page_mut(PageId(118)).unwrap();
+        guard.page_mut().add_cell(0, b"key", b"value").unwrap();
+        drop(guard);
+        let Lookup::Miss(load) = manager.lookup(PageId(120)) else { panic!() };
+        let guard = load.finish_with_strategy(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap(), &mut strategy, clean).unwrap();
+        assert_eq!(buffer_ids[..2].contains(&guard.buffer_id()), false);
+        assert_eq!(manager.fetch_page(PageId(118)).is_some(), true);
+
+        // A bulk load writes back its own pages to reuse the frames
+        let mut strategy = AccessStrategy::new(StrategyKind::BulkWrite, 1);
+        let mut written = vec![];
+        for i in 200..203 {
+            let Lookup::Miss(load) = manager.lookup(PageId(i)) else { panic!() };
+            let mut guard = load.finish_with_strategy(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap(), &mut strategy, |page_id, _| {
+                written.push(page_id);
+                Ok(())
+            }).unwrap().into_write();
+            guard.page_mut().add_cell(0, b"key", b"value").unwrap();
+        }
+        assert_eq!(written, vec![PageId(200), PageId(201)]);
     }
-}
\ No newline at end of file
+
+    // Every page in the page table is mapped to the frame holding it, and nothing else is
+    fn assert_coherent(manager: &BufferManager) {
+        let mut mapped = 0;
+        for (index, buffer) in manager.frames.iter().enumerate() {
+            let page_id = buffer.page_id();
+            if page_id.is_valid() {
+                assert_eq!(manager.page_table.lock(page_id).get(&page_id), Some(&Entry::Loaded(BufferId(index as u32))));
+                mapped += 1;
+            }
+        }
+        assert_eq!(manager.page_table.len(), mapped);
+    }
+
+    #[test]
+    fn test_churn() {
+        for policy in Policy::ALL {
+            let manager = BufferManager::with_policy(4, PAGE_SIZE, policy).unwrap();
+            let mut strategy = AccessStrategy::new(StrategyKind::BulkRead, 1);
+            let mut loaded = 0;
+            // Pages are accessed in a pseudo-random order, many more than the pool holds
+            for (i, index) in random_indices(1, 32).take(2000).enumerate() {
+                let page_id = PageId(1 + index as u32);
+                let guard = match manager.lookup(page_id) {
+                    Lookup::Hit(guard) => guard,
+                    Lookup::Miss(load) => {
+                        let page = leaf_page(Some((&page_id.to_u32().to_be_bytes(), b"value")));
+                        loaded += 1;
+                        if i % 3 == 0 {
+                            load.finish_with_strategy(page, &mut strategy, clean).unwrap()
+                        } else {
+                            load.finish(page, clean).unwrap()
+                        }
+                    }
+                    Lookup::Loading => unreachable!(),
+                };
+                assert_eq!(guard.page_id(), page_id, "{:?}", policy);
+                assert_eq!(guard.page().cell_view(0).body()[..4], page_id.to_u32().to_be_bytes(), "{:?}", policy);
+                drop(guard);
+                assert_coherent(&manager);
+            }
+            // The pool has been churned through
+            assert_eq!(loaded > 1000, true, "{:?}", policy);
+            assert_eq!(manager.page_table.len(), 4);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_load() {
+        let manager = BufferManager::new(2, PAGE_SIZE).unwrap();
+        let barrier = Barrier::new(2);
+        thread::scope(|s| {
+            let Lookup::Miss(load) = manager.lookup(PageId(1)) else { panic!() };
+            assert_eq!(matches!(manager.try_lookup(PageId(1)), Lookup::Loading), true);
+            let waiter = s.spawn(|| {
+                barrier.wait();
+                // Blocks until the page is loaded, instead of reading it again
+                match manager.lookup(PageId(1)) {
+                    Lookup::Hit(guard) => guard.buffer_id(),
+                    _ => panic!("the page has to be loaded by the other thread"),
+                }
+            });
+            barrier.wait();
+            let guard = load.finish(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap(), clean).unwrap();
+            assert_eq!(waiter.join().unwrap(), guard.buffer_id());
+        });
+
+        // A failed load lets a waiting thread read the page by itself
+        thread::scope(|s| {
+            let Lookup::Miss(load) = manager.lookup(PageId(2)) else { panic!() };
+            let waiter = s.spawn(|| {
+                barrier.wait();
+                matches!(manager.lookup(PageId(2)), Lookup::Miss(_))
+            });
+            barrier.wait();
+            drop(load);
+            assert_eq!(waiter.join().unwrap(), true);
+        });
+    }
+
+    #[test]
+    fn test_concurrent_eviction() {
+        let manager = BufferManager::new(4, PAGE_SIZE).unwrap();
+        thread::scope(|s| {
+            for t in 0..4_u32 {
+                let manager = &manager;
+                s.spawn(move || {
+                    for i in 0..200_u32 {
+                        let page_id = PageId(1 + (i * 7 + t) % 16);
+                        let guard = match manager.lookup(page_id) {
+                            Lookup::Hit(guard) => guard,
+                            Lookup::Miss(load) => {
+                                let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+                                page.add_cell(0, &page_id.to_u32().to_be_bytes(), b"value").unwrap();
+                                load.finish(page, clean).unwrap()
+                            }
+                            Lookup::Loading => unreachable!(),
+                        };
+                        // A frame found in the page table always holds the page
+                        assert_eq!(guard.page_id(), page_id);
+                        assert_eq!(guard.page().cell_view(0).body()[..4], page_id.to_u32().to_be_bytes());
+                    }
+                });
+            }
+        });
+        assert_coherent(&manager);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::buffer_manager::BufferId;
+use crate::disk_manager::PageId;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StrategyKind {
+    // A large scan. A frame of the ring dirtied in the meantime is left to the pool,
+    // rather than written back by the scan
+    BulkRead,
+    // A bulk load, which writes back its own pages to reuse their frames
+    BulkWrite,
+}
+
+impl StrategyKind {
+    // The same ring sizes as PostgreSQL
+    pub fn ring_bytes(self) -> usize {
+        match self {
+            StrategyKind::BulkRead => 256 * 1024,
+            StrategyKind::BulkWrite => 16 * 1024 * 1024,
+        }
+    }
+}
+
+// A buffer access strategy after PostgreSQL, requested by a large scan or a bulk load.
+// The pages it loads go into a small private ring of frames, and once the ring is full the frame
+// of the oldest page in the ring is reused, instead of evicting the working set of the others.
+// A frame which cannot be reused, e.g. pinned by another thread, is replaced in the ring by
+// the victim of the replacement policy.
+#[derive(Debug)]
+pub struct AccessStrategy {
+    kind: StrategyKind,
+    // The frames and the pages loaded into them through the ring
+    ring: Vec<Option<(BufferId, PageId)>>,
+    current: usize,
+}
+
+impl AccessStrategy {
+    pub fn new(kind: StrategyKind, ring_size: usize) -> Self {
+        assert!(ring_size > 0, "the ring needs at least a frame");
+        Self {
+            kind,
+            ring: vec![None; ring_size],
+            current: ring_size - 1,
+        }
+    }
+
+    pub fn kind(&self) -> StrategyKind {
+        self.kind
+    }
+
+    pub fn ring_size(&self) -> usize {
+        self.ring.len()
+    }
+
+    // Moves on to the next slot of the ring, and returns the frame loaded through it last time
+    pub fn next_slot(&mut self) -> Option<(BufferId, PageId)> {
+        self.current = (self.current + 1) % self.ring.len();
+        self.ring[self.current]
+    }
+
+    // The page has been loaded into the frame through the current slot
+    pub fn record_load(&mut self, buffer_id: BufferId, page_id: PageId) {
+        self.ring[self.current] = Some((buffer_id, page_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring() {
+        let mut strategy = AccessStrategy::new(StrategyKind::BulkRead, 2);
+        for i in 0..2 {
+            assert_eq!(strategy.next_slot(), None);
+            strategy.record_load(BufferId(i), PageId(i + 1));
+        }
+        assert_eq!(strategy.next_slot(), Some((BufferId(0), PageId(1))));
+        strategy.record_load(BufferId(3), PageId(3));
+        assert_eq!(strategy.next_slot(), Some((BufferId(1), PageId(2))));
+        assert_eq!(strategy.next_slot(), Some((BufferId(3), PageId(3))));
+    }
+}
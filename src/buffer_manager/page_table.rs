@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use crate::buffer_manager::BufferId;
+use crate::disk_manager::PageId;
+
+// Lookups of pages in different shards never contend on the same lock
+const NUMBER_OF_SHARDS: usize = 16;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Entry {
+    // A thread is reading the page from the disk, and the others wait for it instead of reading it twice
+    Loading,
+    Loaded(BufferId),
+}
+
+pub type Entries = HashMap<PageId, Entry>;
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: Mutex<Entries>,
+    loaded: Condvar,
+}
+
+// Maps page ids to the frames holding them.
+// A frame is pinned and unmapped only while the shard of its page is locked, so a frame found
+// in the table always holds the page it is looked up with.
+#[derive(Debug)]
+pub struct PageTable {
+    shards: Vec<Shard>,
+}
+
+impl Default for PageTable {
+    fn default() -> Self {
+        let mut shards = vec![];
+        shards.resize_with(NUMBER_OF_SHARDS, Default::default);
+        Self { shards }
+    }
+}
+
+impl PageTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // A panic while a shard is locked leaves the table in an unknown state, so it is propagated
+    pub fn lock(&self, page_id: PageId) -> MutexGuard<'_, Entries> {
+        self.shard(page_id).entries.lock().unwrap()
+    }
+
+    // Blocks until a page in the shard has been loaded or given up
+    pub fn wait<'a>(&'a self, page_id: PageId, entries: MutexGuard<'a, Entries>) -> MutexGuard<'a, Entries> {
+        self.shard(page_id).loaded.wait(entries).unwrap()
+    }
+
+    pub fn notify(&self, page_id: PageId) {
+        self.shard(page_id).loaded.notify_all();
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.entries.lock().unwrap().len()).sum()
+    }
+
+    fn shard(&self, page_id: PageId) -> &Shard {
+        &self.shards[page_id.to_u32() as usize % NUMBER_OF_SHARDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_wait() {
+        let table = Arc::new(PageTable::new());
+        table.lock(PageId(1)).insert(PageId(1), Entry::Loading);
+
+        let waiter = {
+            let table = Arc::clone(&table);
+            thread::spawn(move || {
+                let mut entries = table.lock(PageId(1));
+                while entries.get(&PageId(1)) == Some(&Entry::Loading) {
+                    entries = table.wait(PageId(1), entries);
+                }
+                entries.get(&PageId(1)).copied()
+            })
+        };
+        // Another page in the same shard wakes the waiter up, and it keeps waiting
+        let other = PageId(1 + NUMBER_OF_SHARDS as u32);
+        table.lock(other).insert(other, Entry::Loaded(BufferId(0)));
+        table.notify(other);
+
+        table.lock(PageId(1)).insert(PageId(1), Entry::Loaded(BufferId(3)));
+        table.notify(PageId(1));
+        assert_eq!(waiter.join().unwrap(), Some(Entry::Loaded(BufferId(3))));
+        assert_eq!(table.len(), 2);
+    }
+}
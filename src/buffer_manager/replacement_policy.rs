@@ -0,0 +1,58 @@
+use crate::buffer_manager::{BufferError, BufferId};
+use crate::buffer_manager::replacement_policy::arc::AdaptiveReplacementCache;
+use crate::buffer_manager::replacement_policy::clock_sweep::ClockSweep;
+use crate::buffer_manager::replacement_policy::lru::Lru;
+use crate::buffer_manager::replacement_policy::lru_k::LruK;
+use crate::buffer_manager::replacement_policy::two_queue::TwoQueue;
+use crate::disk_manager::PageId;
+
+pub mod arc;
+pub mod clock_sweep;
+pub mod lru;
+pub mod lru_k;
+pub mod ordered_list;
+pub mod trace;
+pub mod two_queue;
+
+// Decides which frame is reused once every frame of the pool holds a page.
+// A policy is shared by all the threads using the pool, so it synchronizes its own state.
+pub trait ReplacementPolicy: Send + Sync {
+    // The page has been placed in the frame
+    fn record_insert(&self, buffer_id: BufferId, page_id: PageId);
+    // The page in the frame has been looked up
+    fn record_access(&self, buffer_id: BufferId);
+    // The page has been evicted from the frame
+    fn record_evict(&self, buffer_id: BufferId, page_id: PageId);
+    // Picks a frame for the page to be loaded among the frames evictable() holds for.
+    // None when there is no such frame, i.e. every frame is pinned.
+    fn victim(&self, page_id: PageId, evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId>;
+    // Up to count frames to be evicted soon, in the order they would be, without changing any state.
+    // The background writer writes them in advance, so that evictions rarely wait for a write.
+    fn upcoming_victims(&self, count: usize) -> Vec<BufferId>;
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum Policy {
+    #[default]
+    ClockSweep,
+    Lru,
+    // Evicts the page with the oldest K-th most recent access
+    LruK(usize),
+    TwoQueue,
+    Arc,
+}
+
+impl Policy {
+    // Every policy once, e.g. to compare them on a trace
+    pub const ALL: [Policy; 5] = [Policy::ClockSweep, Policy::Lru, Policy::LruK(2), Policy::TwoQueue, Policy::Arc];
+
+    pub fn build(self, size: usize) -> Result<Box<dyn ReplacementPolicy>, BufferError> {
+        Ok(match self {
+            Policy::ClockSweep => Box::new(ClockSweep::new(size)),
+            Policy::Lru => Box::new(Lru::new()),
+            Policy::LruK(k) => Box::new(LruK::new(size, k)?),
+            Policy::TwoQueue => Box::new(TwoQueue::new(size)),
+            Policy::Arc => Box::new(AdaptiveReplacementCache::new(size)),
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::Mutex;
+
+use crate::buffer_manager::BufferId;
+use crate::buffer_manager::replacement_policy::ordered_list::OrderedList;
+use crate::buffer_manager::replacement_policy::ReplacementPolicy;
+use crate::disk_manager::PageId;
+
+// ARC (Megiddo and Modha). Pages read once and pages read repeatedly are kept in separate LRU
+// lists, and the split of the pool between them adapts to the workload by remembering the pages
+// recently evicted from each list.
+#[derive(Debug)]
+pub struct AdaptiveReplacementCache {
+    size: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // The target size of T1
+    target: usize,
+    // T1, the frames holding pages read once
+    t1: OrderedList<BufferId>,
+    // T2, the frames holding pages read at least twice
+    t2: OrderedList<BufferId>,
+    // B1 and B2, the pages recently evicted from T1 and T2
+    b1: OrderedList<PageId>,
+    b2: OrderedList<PageId>,
+}
+
+impl AdaptiveReplacementCache {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+impl State {
+    // The list to evict from first, for the page to be loaded if it is known
+    fn lists(&self, page_id: Option<PageId>) -> (&OrderedList<BufferId>, &OrderedList<BufferId>) {
+        let in_b2 = page_id.is_some_and(|page_id| self.b2.contains(page_id));
+        let from_t1 = !self.t1.is_empty()
+            && (self.t1.len() > self.target || (self.t1.len() == self.target && in_b2));
+        if from_t1 { (&self.t1, &self.t2) } else { (&self.t2, &self.t1) }
+    }
+
+    // T1 and B1 together never exceed the pool, and all the lists never exceed twice the pool
+    fn trim(&mut self, size: usize) {
+        while self.t1.len() + self.b1.len() > size && self.b1.pop_front().is_some() {}
+        while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > size * 2 && self.b2.pop_front().is_some() {}
+    }
+}
+
+impl ReplacementPolicy for AdaptiveReplacementCache {
+    fn record_insert(&self, buffer_id: BufferId, page_id: PageId) {
+        let mut state = self.state.lock().unwrap();
+        if state.b1.contains(page_id) {
+            // T1 was too small to keep the page
+            let delta = (state.b2.len() / state.b1.len()).max(1);
+            state.target = (state.target + delta).min(self.size);
+            state.b1.remove(page_id);
+            state.t2.push_back(buffer_id);
+        } else if state.b2.contains(page_id) {
+            // T2 was too small to keep the page
+            let delta = (state.b1.len() / state.b2.len()).max(1);
+            state.target = state.target.saturating_sub(delta);
+            state.b2.remove(page_id);
+            state.t2.push_back(buffer_id);
+        } else {
+            state.t1.push_back(buffer_id);
+        }
+        state.trim(self.size);
+    }
+
+    fn record_access(&self, buffer_id: BufferId) {
+        let mut state = self.state.lock().unwrap();
+        if state.t1.remove(buffer_id) || state.t2.contains(buffer_id) {
+            state.t2.push_back(buffer_id);
+        }
+    }
+
+    fn record_evict(&self, buffer_id: BufferId, page_id: PageId) {
+        let mut state = self.state.lock().unwrap();
+        if state.t1.remove(buffer_id) {
+            state.b1.push_back(page_id);
+        } else if state.t2.remove(buffer_id) {
+            state.b2.push_back(page_id);
+        }
+        state.trim(self.size);
+    }
+
+    // Evicts from T1 while it is over its target size, and falls back to the other list
+    // when every frame in the list is pinned
+    fn victim(&self, page_id: PageId, evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        let state = self.state.lock().unwrap();
+        let (first, second) = state.lists(Some(page_id));
+        first.find_front(evictable).or_else(|| second.find_front(evictable))
+    }
+
+    fn upcoming_victims(&self, count: usize) -> Vec<BufferId> {
+        let state = self.state.lock().unwrap();
+        let (first, second) = state.lists(None);
+        first.iter().chain(second.iter()).take(count).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_victim() {
+        let policy = AdaptiveReplacementCache::new(4);
+        for i in 0..4 {
+            policy.record_insert(BufferId(i), PageId(i + 1));
+        }
+        policy.record_access(BufferId(0));
+        // T1 is over the target, which starts from 0
+        assert_eq!(policy.victim(PageId(5), &|_| true), Some(BufferId(1)));
+        assert_eq!(policy.victim(PageId(5), &|buffer_id| buffer_id == BufferId(0)), Some(BufferId(0)));
+        assert_eq!(policy.victim(PageId(5), &|_| false), None);
+        assert_eq!(policy.upcoming_victims(3), vec![BufferId(1), BufferId(2), BufferId(3)]);
+
+        // Page 2 comes back after its eviction, and T1 is given more room
+        policy.record_evict(BufferId(1), PageId(2));
+        policy.record_insert(BufferId(1), PageId(5));
+        policy.record_evict(BufferId(2), PageId(3));
+        policy.record_insert(BufferId(2), PageId(2));
+        let state = policy.state.lock().unwrap();
+        assert_eq!(state.target, 1);
+        assert_eq!(state.t2.contains(BufferId(2)), true);
+        assert_eq!(state.b1.contains(PageId(3)), true);
+    }
+}
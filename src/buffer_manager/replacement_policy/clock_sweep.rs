@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::buffer_manager::BufferId;
+use crate::buffer_manager::replacement_policy::ReplacementPolicy;
+use crate::disk_manager::PageId;
+
+// The hand sweeps the frames and takes a usage off each frame it passes,
+// and the first frame with no usage left is evicted. Lock-free, at the cost of being flushed by scans.
+#[derive(Debug)]
+pub struct ClockSweep {
+    hand: AtomicUsize,
+    usage_counts: Vec<AtomicU32>,
+}
+
+impl ClockSweep {
+    pub fn new(size: usize) -> Self {
+        let mut usage_counts = vec![];
+        usage_counts.resize_with(size, || AtomicU32::new(0));
+        Self {
+            hand: AtomicUsize::new(0),
+            usage_counts,
+        }
+    }
+
+    fn next_buffer_id(&self) -> BufferId {
+        let hand = self.hand.fetch_add(1, Ordering::Relaxed);
+        BufferId((hand % self.usage_counts.len()) as u32)
+    }
+}
+
+impl ReplacementPolicy for ClockSweep {
+    fn record_insert(&self, buffer_id: BufferId, _: PageId) {
+        self.usage_counts[buffer_id.to_usize()].store(1, Ordering::Relaxed);
+    }
+
+    fn record_access(&self, buffer_id: BufferId) {
+        self.usage_counts[buffer_id.to_usize()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_evict(&self, buffer_id: BufferId, _: PageId) {
+        self.usage_counts[buffer_id.to_usize()].store(0, Ordering::Relaxed);
+    }
+
+    // The sweep gives up once it has seen every frame pinned in a row
+    fn victim(&self, _: PageId, evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        let mut pinned_count = 0;
+        loop {
+            if pinned_count >= self.usage_counts.len() {
+                return None;
+            }
+            let buffer_id = self.next_buffer_id();
+            if !evictable(buffer_id) {
+                pinned_count += 1;
+                continue;
+            }
+            pinned_count = 0;
+            let usage_count = &self.usage_counts[buffer_id.to_usize()];
+            let count = usage_count.load(Ordering::Relaxed);
+            if count == 0 {
+                return Some(buffer_id);
+            }
+            // Threads sweeping past the frame at the same time take a single count
+            let _ = usage_count.compare_exchange(count, count - 1, Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+
+    // The frames the hand is about to reach with no usage left, as PostgreSQL's background writer does
+    fn upcoming_victims(&self, count: usize) -> Vec<BufferId> {
+        let hand = self.hand.load(Ordering::Relaxed);
+        let size = self.usage_counts.len();
+        (0..count.min(size))
+            .map(|i| BufferId(((hand + i) % size) as u32))
+            .filter(|buffer_id| self.usage_counts[buffer_id.to_usize()].load(Ordering::Relaxed) == 0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_increment_next_buffer_id() {
+        let policy = ClockSweep::new(2);
+        assert_eq!(policy.next_buffer_id().to_usize(), 0);
+        assert_eq!(policy.next_buffer_id().to_usize(), 1);
+        assert_eq!(policy.next_buffer_id().to_usize(), 0);
+    }
+
+    #[test]
+    fn test_victim() {
+        let policy = ClockSweep::new(3);
+        for i in 0..3 {
+            policy.record_insert(BufferId(i), PageId(i + 1));
+        }
+        policy.record_access(BufferId(0));
+
+        // Frame 0 survives a round more than the others
+        assert_eq!(policy.victim(PageId(4), &|_| true), Some(BufferId(1)));
+        assert_eq!(policy.victim(PageId(4), &|buffer_id| buffer_id != BufferId(2)), Some(BufferId(0)));
+        assert_eq!(policy.victim(PageId(4), &|_| false), None);
+    }
+
+    #[test]
+    fn test_upcoming_victims() {
+        let policy = ClockSweep::new(4);
+        for i in 0..4 {
+            policy.record_insert(BufferId(i), PageId(i + 1));
+        }
+        assert_eq!(policy.victim(PageId(5), &|_| true), Some(BufferId(0)));
+        policy.record_evict(BufferId(0), PageId(1));
+        policy.record_insert(BufferId(0), PageId(5));
+        // The hand has passed every frame once, and frame 0 has just been loaded
+        assert_eq!(policy.upcoming_victims(4), vec![BufferId(1), BufferId(2), BufferId(3)]);
+        assert_eq!(policy.upcoming_victims(2), vec![BufferId(1), BufferId(2)]);
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::Mutex;
+
+use crate::buffer_manager::BufferId;
+use crate::buffer_manager::replacement_policy::ordered_list::OrderedList;
+use crate::buffer_manager::replacement_policy::ReplacementPolicy;
+use crate::disk_manager::PageId;
+
+// Evicts the least recently used page
+#[derive(Debug, Default)]
+pub struct Lru {
+    // The least recently used frame at the front
+    frames: Mutex<OrderedList<BufferId>>,
+}
+
+impl Lru {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ReplacementPolicy for Lru {
+    fn record_insert(&self, buffer_id: BufferId, _: PageId) {
+        self.frames.lock().unwrap().push_back(buffer_id);
+    }
+
+    fn record_access(&self, buffer_id: BufferId) {
+        self.frames.lock().unwrap().push_back(buffer_id);
+    }
+
+    fn record_evict(&self, buffer_id: BufferId, _: PageId) {
+        self.frames.lock().unwrap().remove(buffer_id);
+    }
+
+    fn victim(&self, _: PageId, evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        self.frames.lock().unwrap().find_front(evictable)
+    }
+
+    fn upcoming_victims(&self, count: usize) -> Vec<BufferId> {
+        self.frames.lock().unwrap().iter().take(count).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_victim() {
+        let policy = Lru::new();
+        for i in 0..3 {
+            policy.record_insert(BufferId(i), PageId(i + 1));
+        }
+        policy.record_access(BufferId(0));
+        assert_eq!(policy.victim(PageId(4), &|_| true), Some(BufferId(1)));
+        assert_eq!(policy.victim(PageId(4), &|buffer_id| buffer_id != BufferId(1)), Some(BufferId(2)));
+
+        policy.record_evict(BufferId(1), PageId(2));
+        policy.record_insert(BufferId(1), PageId(4));
+        assert_eq!(policy.victim(PageId(5), &|_| true), Some(BufferId(2)));
+        assert_eq!(policy.victim(PageId(5), &|_| false), None);
+        assert_eq!(policy.upcoming_victims(2), vec![BufferId(2), BufferId(0)]);
+    }
+}
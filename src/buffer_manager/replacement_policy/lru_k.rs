@@ -0,0 +1,155 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::buffer_manager::{BufferError, BufferId};
+use crate::buffer_manager::replacement_policy::{Policy, ReplacementPolicy};
+use crate::disk_manager::PageId;
+
+// Evicts the page whose K-th most recent access is the oldest, and pages accessed less than K
+// times go first in LRU order. A page read once by a scan never pushes out a page used repeatedly.
+// The history of evicted pages is retained for a while, so that a page read again soon is not
+// treated as a new one.
+#[derive(Debug)]
+pub struct LruK {
+    k: usize,
+    // The number of evicted pages whose history is retained
+    retained: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // A logical clock advanced by every access and eviction
+    time: u64,
+    frames: HashMap<BufferId, PageId>,
+    histories: HashMap<PageId, History>,
+    // Evicted pages in the order they were evicted, with the time of the eviction
+    evicted: VecDeque<(PageId, u64)>,
+}
+
+#[derive(Debug, Default)]
+struct History {
+    // The last K accesses, the most recent at the back
+    accesses: VecDeque<u64>,
+    evicted_at: Option<u64>,
+}
+
+impl LruK {
+    pub fn new(size: usize, k: usize) -> Result<Self, BufferError> {
+        if k == 0 {
+            return Err(BufferError::InvalidPolicy(Policy::LruK(k)));
+        }
+        Ok(Self {
+            k,
+            retained: size,
+            state: Mutex::new(State::default()),
+        })
+    }
+}
+
+impl State {
+    // The page with the smallest key is evicted first.
+    // Less than K accesses counts as an infinite distance, and such pages are ordered by the last access.
+    fn eviction_order(&self, page_id: PageId, k: usize) -> (u64, u64) {
+        let accesses = &self.histories[&page_id].accesses;
+        let kth = if accesses.len() < k { 0 } else { accesses[0] };
+        (kth, *accesses.back().unwrap())
+    }
+
+    fn record(&mut self, page_id: PageId, k: usize) {
+        self.time += 1;
+        let history = self.histories.entry(page_id).or_default();
+        history.accesses.push_back(self.time);
+        if history.accesses.len() > k {
+            history.accesses.pop_front();
+        }
+    }
+}
+
+impl ReplacementPolicy for LruK {
+    fn record_insert(&self, buffer_id: BufferId, page_id: PageId) {
+        let mut state = self.state.lock().unwrap();
+        state.frames.insert(buffer_id, page_id);
+        state.record(page_id, self.k);
+        state.histories.get_mut(&page_id).unwrap().evicted_at = None;
+    }
+
+    fn record_access(&self, buffer_id: BufferId) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(&page_id) = state.frames.get(&buffer_id) {
+            state.record(page_id, self.k);
+        }
+    }
+
+    fn record_evict(&self, buffer_id: BufferId, page_id: PageId) {
+        let mut state = self.state.lock().unwrap();
+        state.frames.remove(&buffer_id);
+        state.time += 1;
+        let time = state.time;
+        if let Some(history) = state.histories.get_mut(&page_id) {
+            history.evicted_at = Some(time);
+            state.evicted.push_back((page_id, time));
+        }
+        while state.evicted.len() > self.retained {
+            let (page_id, time) = state.evicted.pop_front().unwrap();
+            // The page may have been loaded again, or evicted again later
+            if state.histories.get(&page_id).and_then(|history| history.evicted_at) == Some(time) {
+                state.histories.remove(&page_id);
+            }
+        }
+    }
+
+    fn victim(&self, _: PageId, evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        let state = self.state.lock().unwrap();
+        state.frames.iter()
+            .filter(|(&buffer_id, _)| evictable(buffer_id))
+            .min_by_key(|(_, &page_id)| state.eviction_order(page_id, self.k))
+            .map(|(&buffer_id, _)| buffer_id)
+    }
+
+    fn upcoming_victims(&self, count: usize) -> Vec<BufferId> {
+        let state = self.state.lock().unwrap();
+        let mut frames: Vec<_> = state.frames.iter().collect();
+        frames.sort_by_key(|(_, &page_id)| state.eviction_order(page_id, self.k));
+        frames.into_iter().take(count).map(|(&buffer_id, _)| buffer_id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_victim() {
+        let policy = LruK::new(3, 2).unwrap();
+        for i in 0..3 {
+            policy.record_insert(BufferId(i), PageId(i + 1));
+        }
+        policy.record_access(BufferId(0));
+        policy.record_access(BufferId(1));
+        policy.record_access(BufferId(0));
+
+        // Accessed only once
+        assert_eq!(policy.victim(PageId(4), &|_| true), Some(BufferId(2)));
+        // The second most recent access of page 2 is older than the one of page 1
+        assert_eq!(policy.victim(PageId(4), &|buffer_id| buffer_id != BufferId(2)), Some(BufferId(1)));
+        assert_eq!(policy.victim(PageId(4), &|_| false), None);
+        assert_eq!(policy.upcoming_victims(3), vec![BufferId(2), BufferId(1), BufferId(0)]);
+    }
+
+    #[test]
+    fn test_retained_history() {
+        let policy = LruK::new(1, 2).unwrap();
+        policy.record_insert(BufferId(0), PageId(2));
+        policy.record_evict(BufferId(0), PageId(2));
+        policy.record_insert(BufferId(0), PageId(1));
+        policy.record_evict(BufferId(0), PageId(1));
+
+        // Page 1 is read again while its history is retained, so it has been accessed twice
+        policy.record_insert(BufferId(0), PageId(1));
+        policy.record_insert(BufferId(1), PageId(3));
+        assert_eq!(policy.victim(PageId(4), &|_| true), Some(BufferId(1)));
+        // The history of page 2 has gone
+        assert_eq!(policy.state.lock().unwrap().histories.contains_key(&PageId(2)), false);
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// Items in the order they were pushed to the back, where any item can be moved to the back
+// or removed in O(log n). The building block of the list-based policies.
+#[derive(Debug)]
+pub struct OrderedList<T> {
+    order: BTreeMap<u64, T>,
+    positions: HashMap<T, u64>,
+    next_position: u64,
+}
+
+impl<T> Default for OrderedList<T> {
+    fn default() -> Self {
+        Self {
+            order: BTreeMap::new(),
+            positions: HashMap::new(),
+            next_position: 0,
+        }
+    }
+}
+
+impl<T: Copy + Eq + Hash> OrderedList<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn contains(&self, item: T) -> bool {
+        self.positions.contains_key(&item)
+    }
+
+    // Moves the item to the back when it is already in the list
+    pub fn push_back(&mut self, item: T) {
+        self.remove(item);
+        self.order.insert(self.next_position, item);
+        self.positions.insert(item, self.next_position);
+        self.next_position += 1;
+    }
+
+    pub fn remove(&mut self, item: T) -> bool {
+        match self.positions.remove(&item) {
+            Some(position) => {
+                self.order.remove(&position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let (_, item) = self.order.pop_first()?;
+        self.positions.remove(&item);
+        Some(item)
+    }
+
+    // From the front to the back
+    pub fn iter(&self) -> impl Iterator<Item=T> + '_ {
+        self.order.values().copied()
+    }
+
+    // The item closest to the front which satisfies the predicate
+    pub fn find_front(&self, predicate: impl Fn(T) -> bool) -> Option<T> {
+        self.order.values().copied().find(|&item| predicate(item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ordered_list() {
+        let mut list = OrderedList::new();
+        for i in 1..=4 {
+            list.push_back(i);
+        }
+        list.push_back(2);
+        assert_eq!(list.len(), 4);
+        assert_eq!(list.remove(3), true);
+        assert_eq!(list.remove(3), false);
+        assert_eq!(list.contains(3), false);
+        assert_eq!(list.find_front(|i| i != 1), Some(4));
+        assert_eq!(list.iter().collect::<Vec<_>>(), vec![1, 4, 2]);
+        assert_eq!(list.pop_front(), Some(1));
+        assert_eq!(list.pop_front(), Some(4));
+        assert_eq!(list.pop_front(), Some(2));
+        assert_eq!(list.pop_front(), None);
+        assert_eq!(list.is_empty(), true);
+    }
+}
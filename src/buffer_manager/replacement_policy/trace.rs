@@ -0,0 +1,151 @@
+use std::fmt;
+use std::io::BufRead;
+
+use anyhow::{Context, Result};
+
+use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, PAGE_SIZE, SlottedPage};
+use crate::buffer_manager::{BufferManager, Lookup};
+use crate::buffer_manager::replacement_policy::Policy;
+use crate::disk_manager::PageId;
+
+// A recorded sequence of page accesses, to compare the replacement policies on a workload.
+// The text format has a page id per line, and lines starting with '#' are comments.
+#[derive(Debug, Default, Clone)]
+pub struct Trace {
+    page_ids: Vec<PageId>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Report {
+    pub policy: Policy,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl Trace {
+    pub fn new(page_ids: Vec<PageId>) -> Self {
+        Self { page_ids }
+    }
+
+    pub fn read(reader: impl BufRead) -> Result<Self> {
+        let mut page_ids = vec![];
+        for (number, line) in reader.lines().enumerate() {
+            let line = line.context("failed to read the trace")?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let page_id = line.parse().with_context(|| format!("invalid page id at line {}", number + 1))?;
+            page_ids.push(PageId(page_id));
+        }
+        Ok(Self { page_ids })
+    }
+
+    pub fn len(&self) -> usize {
+        self.page_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.page_ids.is_empty()
+    }
+
+    // Replays the accesses one by one against a pool of the given size, where a miss loads an empty page
+    pub fn replay(&self, policy: Policy, pool_size: usize) -> Result<Report> {
+        let manager = BufferManager::with_policy(pool_size, PAGE_SIZE, policy).context("failed to new buffer manager")?;
+        let mut report = Report { policy, hits: 0, misses: 0 };
+        for &page_id in self.page_ids.iter() {
+            match manager.lookup(page_id) {
+                Lookup::Hit(_) => report.hits += 1,
+                Lookup::Miss(load) => {
+                    load.finish(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF)?, |_, _| Ok(()))
+                        .with_context(|| format!("failed to load the page {:?}", page_id))?;
+                    report.misses += 1;
+                }
+                Lookup::Loading => unreachable!("no other thread loads the page"),
+            }
+        }
+        Ok(report)
+    }
+
+    pub fn replay_all(&self, policies: &[Policy], pool_size: usize) -> Result<Vec<Report>> {
+        policies.iter().map(|&policy| self.replay(policy, pool_size)).collect()
+    }
+}
+
+impl Report {
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / total as f64
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<12} hits: {:>8} misses: {:>8} hit ratio: {:.3}", format!("{:?}", self.policy), self.hits, self.misses, self.hit_ratio())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bursts of lookups through a few hot index pages, each followed by a full scan of a table
+    // larger than the pool, during which the lookups go on at a lower rate
+    fn scan_heavy_trace() -> Trace {
+        let mut page_ids = vec![];
+        let mut lookups = (1..=8).cycle();
+        for _ in 0..20 {
+            page_ids.extend(lookups.by_ref().take(32).map(PageId));
+            for page_id in 100..300 {
+                page_ids.push(PageId(page_id));
+                if page_id % 4 == 0 {
+                    page_ids.push(PageId(lookups.next().unwrap()));
+                }
+            }
+        }
+        Trace::new(page_ids)
+    }
+
+    #[test]
+    fn test_read() {
+        let trace = Trace::read("# recorded trace\n1\n\n 2 \n1\n".as_bytes()).unwrap();
+        assert_eq!(trace.page_ids, vec![PageId(1), PageId(2), PageId(1)]);
+
+        let err = Trace::read("1\nroot\n".as_bytes()).err().unwrap();
+        assert_eq!(err.to_string(), "invalid page id at line 2");
+    }
+
+    #[test]
+    fn test_replay() {
+        let trace = Trace::new([1, 2, 3, 1, 4, 1].map(PageId).to_vec());
+        for report in trace.replay_all(&Policy::ALL, 3).unwrap() {
+            assert_eq!(report.hits + report.misses, trace.len());
+            // Page 1 is read again before anything is evicted
+            assert_eq!(report.hits >= 1, true);
+        }
+        assert_eq!(trace.replay(Policy::LruK(0), 3).is_err(), true);
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        let trace = scan_heavy_trace();
+        let reports = trace.replay_all(&Policy::ALL, 32).unwrap();
+        assert_eq!(reports.iter().map(|report| report.policy).collect::<Vec<_>>(), Policy::ALL.to_vec());
+        for report in reports.iter() {
+            assert_eq!(report.hits + report.misses, trace.len());
+            // Every policy keeps the hot pages across the bursts of lookups at least
+            assert_eq!(report.hits > 0, true, "{}", report);
+        }
+
+        // The scans flush the hot pages out of the clock and the LRU list,
+        // while the others keep them in the pool
+        let hit_ratio = |policy| reports.iter().find(|report| report.policy == policy).unwrap().hit_ratio();
+        for policy in [Policy::LruK(2), Policy::TwoQueue, Policy::Arc] {
+            assert_eq!(hit_ratio(policy) > hit_ratio(Policy::ClockSweep), true, "{:?}", policy);
+            assert_eq!(hit_ratio(policy) > hit_ratio(Policy::Lru), true, "{:?}", policy);
+        }
+    }
+}
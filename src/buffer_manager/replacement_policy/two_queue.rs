@@ -0,0 +1,120 @@
+use std::sync::Mutex;
+
+use crate::buffer_manager::BufferId;
+use crate::buffer_manager::replacement_policy::ordered_list::OrderedList;
+use crate::buffer_manager::replacement_policy::ReplacementPolicy;
+use crate::disk_manager::PageId;
+
+// 2Q (Johnson and Shasha). A page read for the first time enters a FIFO queue, and it is promoted
+// to the LRU queue only when it is read again after it has been evicted from the FIFO queue,
+// so pages read once by a scan pass through the FIFO queue without touching the LRU queue.
+#[derive(Debug)]
+pub struct TwoQueue {
+    // The target size of the FIFO queue
+    in_size: usize,
+    // The number of pages remembered after they are evicted from the FIFO queue
+    out_size: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // A1in, the FIFO queue of frames holding pages read once
+    a1_in: OrderedList<BufferId>,
+    // A1out, the pages evicted from A1in
+    a1_out: OrderedList<PageId>,
+    // Am, the LRU queue of frames holding pages read repeatedly
+    am: OrderedList<BufferId>,
+}
+
+impl TwoQueue {
+    // The sizes recommended by the paper, a quarter of the pool for A1in and a half for A1out
+    pub fn new(size: usize) -> Self {
+        Self {
+            in_size: (size / 4).max(1),
+            out_size: (size / 2).max(1),
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+impl TwoQueue {
+    // The queue to evict from first
+    fn queues<'a>(&self, state: &'a State) -> (&'a OrderedList<BufferId>, &'a OrderedList<BufferId>) {
+        if state.a1_in.len() > self.in_size || state.am.is_empty() {
+            (&state.a1_in, &state.am)
+        } else {
+            (&state.am, &state.a1_in)
+        }
+    }
+}
+
+impl ReplacementPolicy for TwoQueue {
+    fn record_insert(&self, buffer_id: BufferId, page_id: PageId) {
+        let mut state = self.state.lock().unwrap();
+        if state.a1_out.remove(page_id) {
+            state.am.push_back(buffer_id);
+        } else {
+            state.a1_in.push_back(buffer_id);
+        }
+    }
+
+    // Accesses in A1in are not counted, since they are likely correlated with the first one
+    fn record_access(&self, buffer_id: BufferId) {
+        let mut state = self.state.lock().unwrap();
+        if state.am.contains(buffer_id) {
+            state.am.push_back(buffer_id);
+        }
+    }
+
+    fn record_evict(&self, buffer_id: BufferId, page_id: PageId) {
+        let mut state = self.state.lock().unwrap();
+        if state.a1_in.remove(buffer_id) {
+            state.a1_out.push_back(page_id);
+            if state.a1_out.len() > self.out_size {
+                state.a1_out.pop_front();
+            }
+        } else {
+            state.am.remove(buffer_id);
+        }
+    }
+
+    // Evicts from A1in while it is over its target size, and falls back to the other queue
+    // when every frame in the queue is pinned
+    fn victim(&self, _: PageId, evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        let state = self.state.lock().unwrap();
+        let (first, second) = self.queues(&state);
+        first.find_front(evictable).or_else(|| second.find_front(evictable))
+    }
+
+    fn upcoming_victims(&self, count: usize) -> Vec<BufferId> {
+        let state = self.state.lock().unwrap();
+        let (first, second) = self.queues(&state);
+        first.iter().chain(second.iter()).take(count).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_victim() {
+        let policy = TwoQueue::new(4);
+        for i in 0..4 {
+            policy.record_insert(BufferId(i), PageId(i + 1));
+        }
+        // Accesses in A1in do not matter
+        policy.record_access(BufferId(0));
+        assert_eq!(policy.victim(PageId(5), &|_| true), Some(BufferId(0)));
+
+        // Page 1 is read again after it has been evicted, and it is promoted to Am
+        policy.record_evict(BufferId(0), PageId(1));
+        policy.record_insert(BufferId(0), PageId(1));
+        assert_eq!(policy.state.lock().unwrap().am.contains(BufferId(0)), true);
+        assert_eq!(policy.victim(PageId(5), &|_| true), Some(BufferId(1)));
+        assert_eq!(policy.victim(PageId(5), &|buffer_id| buffer_id == BufferId(0)), Some(BufferId(0)));
+        assert_eq!(policy.victim(PageId(5), &|_| false), None);
+        assert_eq!(policy.upcoming_victims(4), vec![BufferId(1), BufferId(2), BufferId(3), BufferId(0)]);
+    }
+}
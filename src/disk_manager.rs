@@ -1,12 +1,28 @@
-use std::borrow::Borrow;
-use std::fs::{File, OpenOptions};
+use std::collections::HashSet;
 use std::io;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
+use thiserror::Error;
 
-use crate::btree::slotted_page::{PAGE_SIZE, SlottedPage};
+use crate::btree::slotted_page::{MAGIC_NUMBER_DOUBLE_WRITE, MAGIC_NUMBER_FREE_LIST, MAGIC_NUMBER_INTERNAL, MAGIC_NUMBER_LEAF, MAGIC_NUMBER_META, PAGE_SIZE, PAGE_VERSION_V2, SlottedPage};
+use crate::disk_manager::double_write::DoubleWriteBuffer;
+use crate::disk_manager::file_store::FileStore;
+use crate::disk_manager::free_list::{FreeListTrunk, trunk_capacity};
+use crate::disk_manager::header::{FileHeader, HEADER_PAGE_ID};
+
+pub mod compressed_store;
+pub mod double_write;
+pub mod encrypted_store;
+#[cfg(test)]
+pub mod fault_store;
+pub mod file_store;
+pub mod free_list;
+pub mod header;
+pub mod memory_store;
+pub mod mmap_store;
+#[cfg(target_os = "linux")]
+pub mod uring_store;
 
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct PageId(pub u32);
@@ -18,94 +34,1097 @@ impl PageId {
     pub fn to_u64(self) -> u64 {
         self.0 as u64
     }
+    // Byte offset of the page in the database file
+    pub fn offset(self, page_size: usize) -> u64 {
+        self.to_u64() * page_size as u64
+    }
+    // PageId(0) is reserved for the file header, so it never points to a regular page
+    pub fn is_valid(self) -> bool {
+        self != HEADER_PAGE_ID
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DiskError {
+    #[error("invalid file header: {0}")]
+    InvalidHeader(String),
+    #[error("unsupported format version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("unsupported version of the page {0:?}: {1}")]
+    UnsupportedPageVersion(PageId, u8),
+    #[error("page size mismatch: the file uses {0} bytes")]
+    PageSizeMismatch(u32),
+    #[error("unsupported page size: {0}")]
+    UnsupportedPageSize(usize),
+    #[error("segment size mismatch: the file uses {0} pages per segment")]
+    SegmentSizeMismatch(u32),
+    #[error("invalid free list trunk page: {0:?}")]
+    InvalidFreeList(PageId),
+    #[error("page is not allocated: {0:?}")]
+    PageNotAllocated(PageId),
+    #[error("page is already free: {0:?}")]
+    PageAlreadyFree(PageId),
+    #[error("database is locked by another process: {0:?}")]
+    Locked(PathBuf),
+    #[error("database is opened read-only")]
+    ReadOnly,
+    #[error("failed to write {} pages in a batch", .0.len())]
+    PartialWrite(Vec<(PageId, anyhow::Error)>),
+    #[error("failed to authenticate the encrypted page: {0:?}")]
+    AuthenticationFailed(PageId),
+    #[error("encryption key is not available: {0}")]
+    UnknownKey(u32),
+    #[error("unknown magic number in the page {0:?}: {1:#x}")]
+    BadMagic(PageId, u32),
+    #[error("check sum mismatch in the page {0:?}")]
+    ChecksumMismatch(PageId),
+    #[error("the page {0:?} holds the page {1:?}")]
+    MisdirectedWrite(PageId, PageId),
+}
+
+const MAGIC_NUMBERS: [u32; 5] = [
+    MAGIC_NUMBER_LEAF,
+    MAGIC_NUMBER_INTERNAL,
+    MAGIC_NUMBER_META,
+    MAGIC_NUMBER_FREE_LIST,
+    MAGIC_NUMBER_DOUBLE_WRITE,
+];
+
+// Stores reject a buffer which is not exactly one page long before any I/O
+pub fn check_buffer_size(buf: &[u8], page_size: usize) -> Result<(), DiskError> {
+    if buf.len() != page_size {
+        return Err(DiskError::PageSizeMismatch(page_size as u32));
+    }
+    Ok(())
+}
+
+// The storage backend underneath DiskManager. It only moves whole pages around,
+// while the file header and the free list are managed by DiskManager.
+pub trait PageStore {
+    // Buffers are exactly page_size() bytes long, see check_buffer_size()
+    fn read_page(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()>;
+    fn write_page(&mut self, page_id: PageId, buf: &[u8]) -> Result<()>;
+    // Backends which can submit many requests at once override these
+    fn read_pages(&mut self, requests: &mut [(PageId, &mut [u8])]) -> Result<()> {
+        for (_, buf) in requests.iter() {
+            check_buffer_size(buf, self.page_size())?;
+        }
+        for (page_id, buf) in requests.iter_mut() {
+            self.read_page(*page_id, buf)?;
+        }
+        Ok(())
+    }
+    // Every page is attempted, and the pages which failed are reported with DiskError::PartialWrite
+    fn write_pages(&mut self, requests: &[(PageId, &[u8])]) -> Result<()> {
+        for (_, buf) in requests {
+            check_buffer_size(buf, self.page_size())?;
+        }
+        let failures: Vec<_> = requests.iter()
+            .filter_map(|(page_id, buf)| self.write_page(*page_id, buf).err().map(|e| (*page_id, e)))
+            .collect();
+        if !failures.is_empty() {
+            return Err(DiskError::PartialWrite(failures).into());
+        }
+        Ok(())
+    }
+    // Extend the store by one zeroed page
+    fn allocate(&mut self) -> Result<PageId>;
+    // Extend the store by count zeroed pages and return the first one.
+    // Backends on a file system override this to reserve the whole extent at once.
+    fn allocate_pages(&mut self, count: usize) -> Result<PageId> {
+        let first = PageId(self.len() as u32);
+        for _ in 0..count {
+            self.allocate()?;
+        }
+        Ok(first)
+    }
+    // Shrink the store to len pages, and the pages beyond are discarded
+    fn truncate(&mut self, len: u64) -> Result<()>;
+    fn sync(&mut self) -> Result<()>;
+    // Number of pages in the store
+    fn len(&self) -> u64;
+    // Fixed when the store is created, and recorded in the file header
+    fn page_size(&self) -> usize;
+    // Recorded in the file header as well, and 0 when the store is not split into segments
+    fn pages_per_segment(&self) -> u32 {
+        0
+    }
+}
+
+// When page writes are made durable.
+// CompressedStore syncs its data file on every write regardless, since its page map must never
+// point to data which is not durable, and the policy applies to its page map only.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum SyncPolicy {
+    // Leave it to the OS, sync() is a no-op
+    Never,
+    // Sync after every page write
+    EveryWrite,
+    // Sync only when sync() is called explicitly, e.g. on a flush or a checkpoint
+    #[default]
+    OnFlush,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DiskOptions {
+    pub sync_policy: SyncPolicy,
+    // The number of pages in the double-write area reserved on create, and 0 disables it.
+    // An existing database keeps the setting recorded in its header.
+    // Pages in the area are always synced, regardless of the sync policy.
+    pub double_write_slots: usize,
+    // The number of pages the store is extended by at a time, so that a growing file is not
+    // fragmented on disk. 0 grows it one page at a time.
+    pub extent_size: usize,
 }
 
-/*
- * TODO: Maybe I need to implement B-Tree File header including next_page_id, free_page_tables and stuff
- */
-pub struct DiskManager {
-    file: File,
-    next_page_id: PageId,
+pub struct DiskManager<S: PageStore> {
+    store: S,
+    header: FileHeader,
+    options: DiskOptions,
+    double_write: Option<DoubleWriteBuffer>,
+    // Every page on the free list, read from the list when a page is freed for the first time
+    free_page_ids: Option<HashSet<PageId>>,
 }
 
-impl DiskManager {
+impl DiskManager<FileStore> {
     pub fn new(file_path: impl AsRef<Path>) -> Result<Self> {
-        let err = format!("failed to open file, file_path: {:?}", file_path.as_ref());
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(file_path)
-            .context(err)?;
-        Ok(Self {
-            file,
-            next_page_id: PageId(0),
-        })
+        let store = FileStore::open(file_path)?;
+        Self::open(store)
+    }
+}
+
+impl<S: PageStore> DiskManager<S> {
+    pub fn open(store: S) -> Result<Self> {
+        Self::with_options(store, DiskOptions::default())
+    }
+
+    pub fn with_options(store: S, options: DiskOptions) -> Result<Self> {
+        let is_new = store.len() == 0;
+        let double_write_slots = options.double_write_slots;
+        let header = FileHeader::new(store.page_size());
+        let mut manager = Self {
+            store,
+            header,
+            options,
+            double_write: None,
+            free_page_ids: None,
+        };
+        if is_new {
+            manager.store.allocate().context("failed to allocate the header page")?;
+            if double_write_slots > 0 {
+                let buffer = DoubleWriteBuffer::create(&mut manager.store, double_write_slots)?;
+                manager.header.double_write_slots = double_write_slots as u32;
+                manager.header.next_page_id = buffer.end_page_id();
+                manager.double_write = Some(buffer);
+            }
+            manager.header.allocated_end = manager.header.next_page_id;
+            manager.header.pages_per_segment = manager.store.pages_per_segment();
+            manager.write_header().context("failed to initialize the file header")?;
+            manager.sync()?;
+        } else {
+            let mut page = manager.read_page(HEADER_PAGE_ID).context("failed to read the file header")?;
+            if !page.verify_check_sum() {
+                // The header itself may have been torn, and then its copy is in the double-write area
+                if let Some(mut buffer) = DoubleWriteBuffer::load(&mut manager.store)? {
+                    buffer.recover(&mut manager.store).context("failed to recover pages from the double-write area")?;
+                    page = manager.read_page(HEADER_PAGE_ID).context("failed to read the file header")?;
+                }
+            }
+            manager.header = FileHeader::from_page(&mut page)?;
+            // The pages would be looked up in the wrong segment files
+            if manager.header.pages_per_segment != manager.store.pages_per_segment() {
+                return Err(DiskError::SegmentSizeMismatch(manager.header.pages_per_segment).into());
+            }
+            // The store is grown before the header records it and truncated after, so it is never shorter
+            let allocated_end = manager.header.allocated_end;
+            if allocated_end.to_u32() < manager.header.next_page_id.to_u32() || allocated_end.to_u64() > manager.store.len() {
+                return Err(DiskError::InvalidHeader(format!("allocated end {:?} does not match the store", allocated_end)).into());
+            }
+            // Pages left by a crash before the header was updated are preallocated from now on
+            manager.header.allocated_end = PageId(manager.store.len() as u32);
+            if manager.header.double_write_slots > 0 {
+                let mut buffer = DoubleWriteBuffer::load(&mut manager.store)?
+                    .ok_or_else(|| DiskError::InvalidHeader("the double-write area is missing".to_string()))?;
+                buffer.recover(&mut manager.store).context("failed to recover pages from the double-write area")?;
+                manager.double_write = Some(buffer);
+            }
+        }
+
+        Ok(manager)
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.store.page_size()
     }
 
     pub fn next_page_id(&self) -> &PageId {
-        &self.next_page_id
+        &self.header.next_page_id
+    }
+
+    pub fn root_page_id(&self) -> &PageId {
+        &self.header.root_page_id
+    }
+
+    pub fn allocated_end(&self) -> &PageId {
+        &self.header.allocated_end
+    }
+
+    pub fn checkpoint_id(&self) -> u32 {
+        self.header.checkpoint_id
+    }
+
+    pub fn set_root_page_id(&mut self, page_id: PageId) -> Result<()> {
+        self.header.root_page_id = page_id;
+        self.write_header()
+    }
+
+    // Reuse a page from the free list first, then the preallocated pages,
+    // and grow the file by an extent only when both are used up
+    pub fn allocate_page(&mut self) -> Result<PageId> {
+        let head = self.header.free_list_head;
+        if head.is_valid() {
+            let page = self.fetch_page(head).context("failed to read the free list")?;
+            let mut trunk = FreeListTrunk::from_page(head, page)?;
+            if let Some(page_id) = trunk.pop() {
+                self.write_page(head, &trunk.into_page()).context("failed to update the free list")?;
+                self.mark_allocated(page_id);
+                return Ok(page_id);
+            }
+            // The trunk is empty, so hand out the trunk page itself
+            self.header.free_list_head = trunk.next_trunk_page_id();
+            self.write_header()?;
+            self.mark_allocated(head);
+            return Ok(head);
+        }
+
+        let page_id = self.header.next_page_id;
+        if page_id.to_u32() >= self.header.allocated_end.to_u32() {
+            self.extend(self.options.extent_size.max(1), 1)?;
+        }
+        self.header.next_page_id = PageId(page_id.to_u32() + 1);
+        self.write_header()?;
+
+        Ok(page_id)
+    }
+
+    // Reserve space for at least count more pages ahead of a bulk load, so that the file grows only once
+    pub fn preallocate(&mut self, count: usize) -> Result<()> {
+        let end = self.header.next_page_id.to_u64() + count as u64;
+        if end <= self.header.allocated_end.to_u64() {
+            return Ok(());
+        }
+        let count = (end - self.header.allocated_end.to_u64()) as usize;
+        self.extend(count.max(self.options.extent_size), count)?;
+        self.write_header()
+    }
+
+    // Grows the store by count pages, or by the minimum when the file system has no room for all of them.
+    // The caller writes the header with the new allocated end.
+    fn extend(&mut self, count: usize, minimum: usize) -> Result<()> {
+        let mut ret = self.store.allocate_pages(count);
+        if count > minimum && ret.as_ref().is_err_and(is_no_space) {
+            ret = self.store.allocate_pages(minimum);
+        }
+        // A failed extension may have grown some segments before the one which failed
+        self.header.allocated_end = PageId(self.store.len() as u32);
+        ret.with_context(|| format!("failed to extend the store by {} pages", count))?;
+        Ok(())
+    }
+
+    pub fn free_page(&mut self, page_id: PageId) -> Result<()> {
+        if !page_id.is_valid() {
+            return Err(DiskError::PageNotAllocated(page_id).into());
+        }
+        self.check_allocated(page_id)?;
+        // A page on the list twice would be handed out twice
+        if self.free_page_ids()?.contains(&page_id) {
+            return Err(DiskError::PageAlreadyFree(page_id).into());
+        }
+        let head = self.header.free_list_head;
+        if head.is_valid() {
+            let page = self.fetch_page(head).context("failed to read the free list")?;
+            let mut trunk = FreeListTrunk::from_page(head, page)?;
+            if !trunk.is_full() {
+                trunk.push(page_id);
+                self.write_page(head, &trunk.into_page()).context("failed to update the free list")?;
+                self.mark_free(page_id);
+                return Ok(());
+            }
+        }
+        // The head trunk is full (or missing), so the freed page becomes the new head trunk
+        let trunk = FreeListTrunk::new(self.page_size(), head)?;
+        self.write_page(page_id, &trunk.into_page()).context("failed to write a free list trunk")?;
+        self.header.free_list_head = page_id;
+        self.write_header()?;
+        self.mark_free(page_id);
+
+        Ok(())
+    }
+
+    fn free_page_ids(&mut self) -> Result<&HashSet<PageId>> {
+        if self.free_page_ids.is_none() {
+            let (trunks, leaves) = self.free_pages()?;
+            self.free_page_ids = Some(trunks.into_iter().chain(leaves).collect());
+        }
+        Ok(self.free_page_ids.as_ref().unwrap())
+    }
+
+    fn mark_free(&mut self, page_id: PageId) {
+        if let Some(free_page_ids) = self.free_page_ids.as_mut() {
+            free_page_ids.insert(page_id);
+        }
+    }
+
+    fn mark_allocated(&mut self, page_id: PageId) {
+        if let Some(free_page_ids) = self.free_page_ids.as_mut() {
+            free_page_ids.remove(&page_id);
+        }
+    }
+
+    // Gives the free pages at the end of the file back to the file system, and returns how many pages were removed.
+    // The new free list is synced before the header points to it, and the header is synced before the file
+    // is truncated, so a crash in between leaves either the old list or unused pages at the end of the file,
+    // which are preallocated pages on the next open.
+    pub fn truncate_free_tail(&mut self) -> Result<u64> {
+        let (trunks, leaves) = self.free_pages()?;
+        let free: HashSet<PageId> = trunks.iter().chain(leaves.iter()).copied().collect();
+        // Neither the header page nor the double-write area is ever on the free list
+        let mut end = self.header.next_page_id;
+        while free.contains(&PageId(end.to_u32() - 1)) {
+            end = PageId(end.to_u32() - 1);
+        }
+        let remaining = |end: PageId| {
+            let mut remaining: Vec<PageId> = free.iter().copied().filter(|page_id| page_id.to_u32() < end.to_u32()).collect();
+            remaining.sort_by_key(|page_id| page_id.to_u32());
+            remaining
+        };
+        // Keep free pages at the end until the new list has enough pages for its trunks
+        while end != self.header.next_page_id && !self.can_rebuild_free_list(&remaining(end), &trunks) {
+            end = PageId(end.to_u32() + 1);
+        }
+        if end == self.header.next_page_id && self.store.len() == end.to_u64() {
+            return Ok(0);
+        }
+
+        if end != self.header.next_page_id {
+            self.rebuild_free_list(remaining(end), &trunks)?;
+            self.sync_store().context("failed to sync the free list")?;
+            self.header.next_page_id = end;
+            // Read again from the new list when needed
+            self.free_page_ids = None;
+        }
+        self.header.allocated_end = end;
+        self.write_header()?;
+        self.sync_store().context("failed to sync the file header")?;
+        let removed = self.store.len() - end.to_u64();
+        self.store.truncate(end.to_u64()).context("failed to truncate the store")?;
+
+        Ok(removed)
+    }
+
+    // Every page on the free list, as the trunk pages and the page ids they hold
+    fn free_pages(&mut self) -> Result<(Vec<PageId>, Vec<PageId>)> {
+        let mut trunks = vec![];
+        let mut leaves = vec![];
+        let mut page_id = self.header.free_list_head;
+        while page_id.is_valid() {
+            if trunks.contains(&page_id) {
+                return Err(DiskError::InvalidFreeList(page_id).into());
+            }
+            let page = self.fetch_page(page_id).context("failed to read the free list")?;
+            let trunk = FreeListTrunk::from_page(page_id, page)?;
+            trunks.push(page_id);
+            leaves.extend(trunk.page_ids());
+            page_id = trunk.next_trunk_page_id();
+        }
+
+        Ok((trunks, leaves))
+    }
+
+    fn number_of_trunks(&self, number_of_pages: usize) -> usize {
+        number_of_pages.div_ceil(trunk_capacity(self.page_size()) + 1)
+    }
+
+    fn can_rebuild_free_list(&self, page_ids: &[PageId], old_trunks: &[PageId]) -> bool {
+        let candidates = page_ids.iter().filter(|page_id| !old_trunks.contains(page_id)).count();
+        candidates >= self.number_of_trunks(page_ids.len())
+    }
+
+    // The new trunks are never written to trunks of the current list, so that the current list stays intact
+    // until the header points to the new one. The old trunks are leaves of the new list.
+    fn rebuild_free_list(&mut self, page_ids: Vec<PageId>, old_trunks: &[PageId]) -> Result<()> {
+        let number_of_trunks = self.number_of_trunks(page_ids.len());
+        let (mut candidates, old): (Vec<PageId>, Vec<PageId>) = page_ids.into_iter().partition(|page_id| !old_trunks.contains(page_id));
+        let mut leaves = candidates.split_off(number_of_trunks);
+        leaves.extend(old);
+
+        let mut head = PageId(0);
+        let mut leaves = leaves.into_iter();
+        for trunk_page_id in candidates {
+            let mut trunk = FreeListTrunk::new(self.page_size(), head)?;
+            for page_id in leaves.by_ref().take(trunk.capacity()) {
+                trunk.push(page_id);
+            }
+            self.write_page(trunk_page_id, &trunk.into_page()).context("failed to write a free list trunk")?;
+            head = trunk_page_id;
+        }
+        self.header.free_list_head = head;
+
+        Ok(())
     }
 
+    // The page is written with its own page id stamped in the header
     pub fn write_page(&mut self, page_id: PageId, page: &SlottedPage) -> Result<()> {
-        let offset = page_id.to_u64();
-        self.file.seek(SeekFrom::Start(offset)).context("failed to seek the file")?;
-        self.file.write_all(page.to_bytes()).context("failed to write bytes into the file")?;
+        self.check_allocated(page_id)?;
+        self.check_page_size(page)?;
+        let page = stamp_page_id(page_id, page)?;
+        match self.double_write.as_mut() {
+            Some(buffer) => buffer.write_pages(&mut self.store, &[(page_id, page.to_bytes())])?,
+            None => self.store.write_page(page_id, page.to_bytes())?,
+        }
+        if self.options.sync_policy == SyncPolicy::EveryWrite {
+            self.store.sync()?;
+        }
+
+        Ok(())
+    }
+
+    // Pages which could not be written are reported all together with DiskError::PartialWrite
+    pub fn write_pages(&mut self, pages: &[(PageId, &SlottedPage)]) -> Result<()> {
+        let mut failures = vec![];
+        let mut stamped = vec![];
+        for &(page_id, page) in pages {
+            match self.check_allocated(page_id).and_then(|_| self.check_page_size(page)).and_then(|_| Ok(stamp_page_id(page_id, page)?)) {
+                Ok(page) => stamped.push((page_id, page)),
+                Err(err) => failures.push((page_id, err)),
+            }
+        }
+        let requests: Vec<(PageId, &[u8])> = stamped.iter().map(|(page_id, page)| (*page_id, page.to_bytes())).collect();
+        let ret = match self.double_write.as_mut() {
+            Some(buffer) => buffer.write_pages(&mut self.store, &requests),
+            None => self.store.write_pages(&requests),
+        };
+        if let Err(err) = ret {
+            match err.downcast::<DiskError>() {
+                Ok(DiskError::PartialWrite(store_failures)) => failures.extend(store_failures),
+                Ok(err) => return Err(err.into()),
+                Err(err) => return Err(err),
+            }
+        }
+        if !failures.is_empty() {
+            failures.sort_by_key(|(page_id, _)| page_id.to_u32());
+            return Err(DiskError::PartialWrite(failures).into());
+        }
+        if self.options.sync_policy == SyncPolicy::EveryWrite {
+            self.store.sync()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        match self.options.sync_policy {
+            SyncPolicy::Never => Ok(()),
+            SyncPolicy::EveryWrite | SyncPolicy::OnFlush => self.sync_store(),
+        }
+    }
+
+    // Makes every page written so far durable, and then records the checkpoint in the file header.
+    // Regardless of the sync policy, so that the checkpoint id in the header can be trusted.
+    pub fn checkpoint(&mut self) -> Result<u32> {
+        self.sync_store().context("failed to sync pages for the checkpoint")?;
+        self.header.checkpoint_id += 1;
+        self.write_header()?;
+        self.sync_store().context("failed to sync the checkpoint")?;
+
+        Ok(self.header.checkpoint_id)
+    }
+
+    // Regardless of the sync policy
+    fn sync_store(&mut self) -> Result<()> {
+        self.store.sync()?;
+        if let Some(buffer) = self.double_write.as_mut() {
+            buffer.mark_synced();
+        }
+        Ok(())
+    }
 
+    // Pages in the double-write area are not visible from outside
+    fn check_allocated(&self, page_id: PageId) -> Result<()> {
+        let reserved = self.double_write.as_ref().is_some_and(|buffer| buffer.contains(page_id));
+        if reserved || page_id.to_u32() >= self.header.next_page_id.to_u32() {
+            return Err(DiskError::PageNotAllocated(page_id).into());
+        }
+        Ok(())
+    }
+
+    fn check_page_size(&self, page: &SlottedPage) -> Result<()> {
+        if page.page_size() != self.page_size() {
+            return Err(DiskError::PageSizeMismatch(self.page_size() as u32).into());
+        }
         Ok(())
     }
 
+    fn write_header(&mut self) -> Result<()> {
+        let page = self.header.to_page()?;
+        self.write_page(HEADER_PAGE_ID, &page).context("failed to write the file header")
+    }
+
+    // Pages are verified on every read, and a broken page is reported with its page id
     pub fn fetch_page(&mut self, page_id: PageId) -> Result<SlottedPage> {
-        let offset = page_id.to_u64();
-        let mut buf = [0 as u8; PAGE_SIZE];
-        self.file.seek(SeekFrom::Start(offset)).context("failed to seek the file")?;
-        self.file.read_exact(&mut buf).context("failed to read bytes from the file")?;
+        self.check_allocated(page_id)?;
+        let mut page = self.read_page(page_id)?;
+        verify_page(page_id, &mut page)?;
+
+        Ok(page)
+    }
+
+    fn read_page(&mut self, page_id: PageId) -> Result<SlottedPage> {
+        let mut page = SlottedPage::zeroed(self.page_size())?;
+        self.store.read_page(page_id, page.to_bytes_mut())?;
+
+        Ok(page)
+    }
+
+    pub fn fetch_pages(&mut self, page_ids: &[PageId]) -> Result<Vec<SlottedPage>> {
+        for &page_id in page_ids {
+            self.check_allocated(page_id)?;
+        }
+        let mut pages = page_ids.iter().map(|_| SlottedPage::zeroed(self.page_size())).collect::<Result<Vec<_>, _>>()?;
+        let mut requests: Vec<(PageId, &mut [u8])> = page_ids.iter()
+            .zip(pages.iter_mut())
+            .map(|(&page_id, page)| (page_id, page.to_bytes_mut()))
+            .collect();
+        self.store.read_pages(&mut requests)?;
+        for (&page_id, page) in page_ids.iter().zip(pages.iter_mut()) {
+            verify_page(page_id, page)?;
+        }
+
+        Ok(pages)
+    }
+}
+
+fn is_no_space(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| e.downcast_ref::<io::Error>().and_then(|e| e.raw_os_error()) == Some(libc::ENOSPC))
+}
 
-        Ok(SlottedPage::wrap(buf))
+fn stamp_page_id(page_id: PageId, page: &SlottedPage) -> Result<SlottedPage, DiskError> {
+    let mut page = SlottedPage::from_bytes(page.to_bytes())?;
+    page.header_view_mut().page_id_mut().write(page_id.to_u32());
+    let sum = page.check_sum();
+    page.header_view_mut().check_sum_mut().write(sum);
+    Ok(page)
+}
+
+fn verify_page(page_id: PageId, page: &mut SlottedPage) -> Result<(), DiskError> {
+    let magic_number = page.header_view().magic_number().read();
+    if !MAGIC_NUMBERS.contains(&magic_number) {
+        return Err(DiskError::BadMagic(page_id, magic_number));
+    }
+    if !page.verify_check_sum() {
+        return Err(DiskError::ChecksumMismatch(page_id));
+    }
+    let stored_page_id = PageId(page.header_view().page_id().read());
+    if stored_page_id != page_id {
+        return Err(DiskError::MisdirectedWrite(page_id, stored_page_id));
     }
+    let version = page.header_view().version().read();
+    if version != PAGE_VERSION_V2 {
+        return Err(DiskError::UnsupportedPageVersion(page_id, version));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs;
-    use std::ptr::write;
 
-    use crate::btree::slotted_page::MAGIC_NUMBER_LEAF;
+    use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, PAGE_SIZES};
+    use crate::disk_manager::memory_store::MemoryStore;
 
     use super::*;
 
-    const DB_PATH: &str = "test1.idb";
+    fn reopen(manager: DiskManager<MemoryStore>) -> DiskManager<MemoryStore> {
+        DiskManager::open(manager.into_store()).unwrap()
+    }
 
-    struct Cleanup;
+    #[derive(Default)]
+    struct CountingStore {
+        store: MemoryStore,
+        writes: usize,
+        syncs: usize,
+    }
 
-    impl Drop for Cleanup {
-        fn drop(&mut self) {
-            fs::remove_file(DB_PATH).expect("failed to remove db file");
+    impl PageStore for CountingStore {
+        fn read_page(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()> {
+            self.store.read_page(page_id, buf)
+        }
+        fn write_page(&mut self, page_id: PageId, buf: &[u8]) -> Result<()> {
+            self.writes += 1;
+            self.store.write_page(page_id, buf)
+        }
+        fn allocate(&mut self) -> Result<PageId> {
+            self.store.allocate()
+        }
+        fn truncate(&mut self, len: u64) -> Result<()> {
+            self.store.truncate(len)
+        }
+        fn sync(&mut self) -> Result<()> {
+            self.syncs += 1;
+            self.store.sync()
+        }
+        fn len(&self) -> u64 {
+            self.store.len()
+        }
+        fn page_size(&self) -> usize {
+            self.store.page_size()
+        }
+        fn pages_per_segment(&self) -> u32 {
+            self.store.pages_per_segment()
         }
     }
 
     #[test]
     fn work_as_expected() {
-        let cleanup = Cleanup;
-        let path = DB_PATH;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
 
-        assert_eq!(Path::new(path).exists(), false);
-        let ret = DiskManager::new(path);
+        assert_eq!(path.exists(), false);
+        let ret = DiskManager::new(&path);
         assert_eq!(ret.is_ok(), true);
-        assert_eq!(Path::new(path).exists(), true);
+        assert_eq!(path.exists(), true);
 
         let mut manager = ret.unwrap();
-        let page_id = PageId(0);
-        let fetch_ret = manager.fetch_page(page_id);
+        let page_id = manager.allocate_page().unwrap();
+        assert_eq!(page_id, PageId(1));
+        let fetch_ret = manager.fetch_page(PageId(2));
         assert_eq!(fetch_ret.is_ok(), false);
+        let err = fetch_ret.err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(PageId(2)))), true);
 
-        let page = SlottedPage::new(MAGIC_NUMBER_LEAF);
+        let page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
         let write_ret = manager.write_page(page_id, &page);
         assert_eq!(write_ret.is_ok(), true);
 
         let fetch_ret = manager.fetch_page(page_id);
         assert_eq!(fetch_ret.is_ok(), true);
         let fetched_page = fetch_ret.unwrap();
-        assert_eq!(fetched_page.header_view().check_sum().read(), 2327031672);
+        assert_eq!(fetched_page.header_view().check_sum().read(), 320274897);
+        assert_eq!(fetched_page.header_view().page_id().read(), page_id.to_u32());
+        assert_eq!(manager.next_page_id(), &PageId(2));
+        drop(manager);
+
+        let mut manager = DiskManager::new(&path).unwrap();
+        assert_eq!(manager.next_page_id(), &PageId(2));
+        assert_eq!(manager.fetch_page(page_id).unwrap().valid(), true);
+    }
+
+    #[test]
+    fn test_verify_on_read() {
+        let mut manager = DiskManager::open(MemoryStore::new()).unwrap();
+        let page_ids: Vec<PageId> = (0..3).map(|_| manager.allocate_page().unwrap()).collect();
+        for &page_id in &page_ids {
+            manager.write_page(page_id, &SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap()).unwrap();
+        }
+        let mut store = manager.into_store();
+        let mut buf = [0; PAGE_SIZE];
+
+        // Unknown magic number
+        store.read_page(page_ids[0], &mut buf).unwrap();
+        buf[0] = 0;
+        store.write_page(page_ids[0], &buf).unwrap();
+        // Flipped bit
+        store.read_page(page_ids[1], &mut buf).unwrap();
+        buf[PAGE_SIZE / 2] ^= 1;
+        store.write_page(page_ids[1], &buf).unwrap();
+        // A valid page written to a wrong place
+        store.read_page(page_ids[0], &mut buf).unwrap();
+        buf[0] = (MAGIC_NUMBER_LEAF >> 24) as u8;
+        store.write_page(page_ids[2], &buf).unwrap();
+
+        let mut manager = DiskManager::open(store).unwrap();
+        let err = manager.fetch_page(page_ids[0]).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::BadMagic(id, 0x00DD56AA)) if *id == page_ids[0]), true);
+        let err = manager.fetch_page(page_ids[1]).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::ChecksumMismatch(id)) if *id == page_ids[1]), true);
+        let err = manager.fetch_page(page_ids[2]).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::MisdirectedWrite(id, found)) if *id == page_ids[2] && *found == page_ids[0]), true);
+        let err = manager.fetch_pages(&page_ids[1..]).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::ChecksumMismatch(_))), true);
+    }
+
+    #[test]
+    fn test_allocate_and_free_page() {
+        let mut manager = DiskManager::open(MemoryStore::new()).unwrap();
+        let page_ids: Vec<PageId> = (0..5).map(|_| manager.allocate_page().unwrap()).collect();
+        assert_eq!(page_ids, (1..=5).map(PageId).collect::<Vec<_>>());
+
+        // Freeing an unallocated page or the header page fails
+        let ret = manager.free_page(PageId(6));
+        assert_eq!(matches!(ret.err().unwrap().downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(PageId(6)))), true);
+        assert_eq!(manager.free_page(HEADER_PAGE_ID).is_err(), true);
+
+        assert_eq!(manager.free_page(PageId(2)).is_ok(), true);
+        assert_eq!(manager.free_page(PageId(4)).is_ok(), true);
+
+        // Freeing a page twice fails, whether the page is a trunk or a leaf, even after a restart
+        let ret = manager.free_page(PageId(2));
+        assert_eq!(matches!(ret.err().unwrap().downcast_ref::<DiskError>(), Some(DiskError::PageAlreadyFree(PageId(2)))), true);
+        let mut manager = reopen(manager);
+        let ret = manager.free_page(PageId(4));
+        assert_eq!(matches!(ret.err().unwrap().downcast_ref::<DiskError>(), Some(DiskError::PageAlreadyFree(PageId(4)))), true);
+
+        // Freed pages are reused before the store grows, even after a restart
+        let mut manager = reopen(manager);
+        let mut reused = vec![manager.allocate_page().unwrap(), manager.allocate_page().unwrap()];
+        reused.sort_by_key(|p| p.to_u32());
+        assert_eq!(reused, vec![PageId(2), PageId(4)]);
+        assert_eq!(manager.allocate_page().unwrap(), PageId(6));
+    }
+
+    #[test]
+    fn test_extents() {
+        let options = DiskOptions { extent_size: 8, ..Default::default() };
+        let mut manager = DiskManager::with_options(MemoryStore::new(), options.clone()).unwrap();
+        assert_eq!(manager.allocated_end(), &PageId(1));
+
+        // The first allocation grows the store by an extent, and the following ones fill it up
+        assert_eq!(manager.allocate_page().unwrap(), PageId(1));
+        assert_eq!(manager.store.len(), 9);
+        assert_eq!(manager.allocated_end(), &PageId(9));
+        for i in 2..9 {
+            assert_eq!(manager.allocate_page().unwrap(), PageId(i));
+        }
+        assert_eq!(manager.store.len(), 9);
+        assert_eq!(manager.allocate_page().unwrap(), PageId(9));
+        assert_eq!(manager.store.len(), 17);
+
+        // A freed page is reused before the preallocated ones
+        assert_eq!(manager.free_page(PageId(3)).is_ok(), true);
+        assert_eq!(manager.allocate_page().unwrap(), PageId(3));
+        assert_eq!(manager.allocate_page().unwrap(), PageId(10));
+
+        // The preallocated range survives a restart
+        let mut manager = DiskManager::with_options(manager.into_store(), options.clone()).unwrap();
+        assert_eq!(manager.next_page_id(), &PageId(11));
+        assert_eq!(manager.allocated_end(), &PageId(17));
+
+        // Space for a bulk load is reserved at once
+        assert_eq!(manager.preallocate(100).is_ok(), true);
+        assert_eq!(manager.store.len(), 111);
+        assert_eq!(manager.allocated_end(), &PageId(111));
+        assert_eq!(manager.preallocate(10).is_ok(), true);
+        assert_eq!(manager.store.len(), 111);
+        for i in 11..111 {
+            assert_eq!(manager.allocate_page().unwrap(), PageId(i));
+        }
+        assert_eq!(manager.store.len(), 111);
+
+        // Pages the header has not recorded, e.g. after a crash, are preallocated on the next open
+        let mut store = manager.into_store();
+        store.allocate_pages(3).unwrap();
+        let mut manager = DiskManager::with_options(store, options.clone()).unwrap();
+        assert_eq!(manager.allocated_end(), &PageId(114));
+        assert_eq!(manager.allocate_page().unwrap(), PageId(111));
+        assert_eq!(manager.store.len(), 114);
+
+        // The store is never shorter than the allocated end in the header
+        let mut store = manager.into_store();
+        store.truncate(113).unwrap();
+        let err = DiskManager::with_options(store, options).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::InvalidHeader(_))), true);
+    }
+
+    #[test]
+    fn test_free_list_spans_multiple_trunks() {
+        let mut manager = DiskManager::open(MemoryStore::new()).unwrap();
+        let count = free_list::trunk_capacity(PAGE_SIZE) * 2 + 10;
+        let page_ids: Vec<PageId> = (0..count).map(|_| manager.allocate_page().unwrap()).collect();
+        for &page_id in page_ids.iter() {
+            assert_eq!(manager.free_page(page_id).is_ok(), true);
+        }
+
+        let mut reused: Vec<u32> = (0..count).map(|_| manager.allocate_page().unwrap().to_u32()).collect();
+        reused.sort();
+        assert_eq!(reused, page_ids.iter().map(|p| p.to_u32()).collect::<Vec<_>>());
+        assert_eq!(manager.allocate_page().unwrap(), PageId(count as u32 + 1));
+    }
+
+    #[test]
+    fn test_truncate_free_tail() {
+        let options = DiskOptions { extent_size: 8, ..Default::default() };
+        let mut manager = DiskManager::with_options(MemoryStore::new(), options.clone()).unwrap();
+        for _ in 0..20 {
+            manager.allocate_page().unwrap();
+        }
+        assert_eq!(manager.store.len(), 25);
+        for page_id in [5, 16, 18, 17, 20, 19] {
+            assert_eq!(manager.free_page(PageId(page_id)).is_ok(), true);
+        }
+
+        // The free pages from 17 and the preallocated ones are removed. 5 is the trunk of the current list
+        // and is never overwritten before the header points to the new list, so 16 is kept for its trunk.
+        assert_eq!(manager.truncate_free_tail().unwrap(), 8);
+        assert_eq!(manager.store.len(), 17);
+        assert_eq!(manager.next_page_id(), &PageId(17));
+        assert_eq!(manager.allocated_end(), &PageId(17));
+        let err = manager.fetch_page(PageId(17)).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(PageId(17)))), true);
+        // 16 is the trunk now, so 5 can take over and 16 is removed as well
+        assert_eq!(manager.truncate_free_tail().unwrap(), 1);
+        assert_eq!(manager.store.len(), 16);
+        assert_eq!(manager.truncate_free_tail().unwrap(), 0);
+
+        let mut manager = DiskManager::with_options(manager.into_store(), options).unwrap();
+        assert_eq!(manager.next_page_id(), &PageId(16));
+        assert_eq!(manager.allocate_page().unwrap(), PageId(5));
+        assert_eq!(manager.allocate_page().unwrap(), PageId(16));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_truncate_free_tail_with_trunks() {
+        let mut manager = DiskManager::open(MemoryStore::new()).unwrap();
+        let count = free_list::trunk_capacity(PAGE_SIZE) * 3;
+        let page_ids: Vec<PageId> = (0..count).map(|_| manager.allocate_page().unwrap()).collect();
+        // Every other page in the first half, and the whole second half
+        let kept: Vec<PageId> = page_ids[..count / 2].iter().step_by(2).copied().collect();
+        let freed: Vec<PageId> = page_ids.iter().filter(|page_id| !kept.contains(page_id)).copied().collect();
+        for &page_id in kept.iter() {
+            manager.write_page(page_id, &SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap()).unwrap();
+        }
+        for &page_id in freed.iter() {
+            assert_eq!(manager.free_page(page_id).is_ok(), true);
+        }
+
+        let end = kept.last().unwrap().to_u32() + 1;
+        assert_eq!(manager.truncate_free_tail().unwrap(), (count as u32 + 1 - end) as u64);
+        assert_eq!(manager.store.len(), end as u64);
+        for &page_id in kept.iter() {
+            assert_eq!(manager.fetch_page(page_id).is_ok(), true);
+        }
+
+        // The free list holds exactly the remaining free pages
+        let mut reused: Vec<PageId> = (0..freed.len()).map(|_| manager.allocate_page().unwrap()).collect();
+        reused.sort_by_key(|page_id| page_id.to_u32());
+        let mut expected: Vec<PageId> = freed.iter().filter(|page_id| page_id.to_u32() < end).copied().collect();
+        expected.extend((end..end + (freed.len() - expected.len()) as u32).map(PageId));
+        assert_eq!(reused, expected);
+    }
+
+    #[test]
+    fn test_read_write_many_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        let number_of_pages = 3000;
+
+        let mut manager = DiskManager::new(&path).unwrap();
+        for i in 0..number_of_pages {
+            let page_id = manager.allocate_page().unwrap();
+            let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+            page.add_cell(0, &page_id.to_u32().to_be_bytes(), &(i as u32).to_be_bytes()).unwrap();
+            assert_eq!(manager.write_page(page_id, &page).is_ok(), true);
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), (number_of_pages + 1) * PAGE_SIZE as u64);
+        drop(manager);
+
+        let mut manager = DiskManager::new(&path).unwrap();
+        for i in 0..number_of_pages {
+            let page_id = PageId(i as u32 + 1);
+            let mut page = manager.fetch_page(page_id).unwrap();
+            assert_eq!(page.valid(), true);
+            assert_eq!(page.cell_view(0).body()[0..4], page_id.to_u32().to_be_bytes());
+            assert_eq!(page.cell_view(0).body()[4..8], (i as u32).to_be_bytes());
+        }
+
+        // Past the end of the file
+        let page_id = PageId(number_of_pages as u32 + 1);
+        let err = manager.fetch_page(page_id).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(p)) if *p == page_id), true);
+        let err = manager.write_page(page_id, &SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap()).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(p)) if *p == page_id), true);
+    }
+
+    #[test]
+    fn test_fetch_pages() {
+        let mut manager = DiskManager::open(MemoryStore::new()).unwrap();
+        let mut page_ids = vec![];
+        for i in 0..10_u32 {
+            let page_id = manager.allocate_page().unwrap();
+            let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+            page.add_cell(0, &i.to_be_bytes(), b"value").unwrap();
+            manager.write_page(page_id, &page).unwrap();
+            page_ids.push(page_id);
+        }
+        page_ids.reverse();
+
+        let ret = manager.fetch_pages(&page_ids);
+        assert_eq!(ret.is_ok(), true);
+        for (i, page) in ret.unwrap().iter_mut().enumerate() {
+            assert_eq!(page.valid(), true);
+            assert_eq!(page.cell_view(0).body()[0..4], (9 - i as u32).to_be_bytes());
+        }
+
+        let err = manager.fetch_pages(&[PageId(1), PageId(11)]).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(PageId(11)))), true);
+    }
+
+    #[test]
+    fn test_write_pages() {
+        let mut manager = DiskManager::open(MemoryStore::new()).unwrap();
+        let page_ids: Vec<PageId> = (0..6).map(|_| manager.allocate_page().unwrap()).collect();
+        let pages: Vec<SlottedPage> = (0..7_u8).map(|i| {
+            let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+            page.add_cell(0, &[i], b"value").unwrap();
+            page
+        }).collect();
+
+        // Out of order, with a page which is not allocated
+        let unallocated = PageId(100);
+        let mut requests: Vec<(PageId, &SlottedPage)> = page_ids.iter().rev().copied().zip(pages.iter()).collect();
+        requests.insert(2, (unallocated, &pages[6]));
+        let err = manager.write_pages(&requests).err().unwrap();
+        match err.downcast_ref::<DiskError>() {
+            Some(DiskError::PartialWrite(failures)) => {
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].0, unallocated);
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+
+        // The other pages are written anyway
+        for (i, page_id) in page_ids.iter().rev().enumerate() {
+            let page = manager.fetch_page(*page_id).unwrap();
+            assert_eq!(page.cell_view(0).body()[0], i as u8);
+        }
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let write_and_sync = |sync_policy| {
+            let options = DiskOptions { sync_policy, ..Default::default() };
+            let mut manager = DiskManager::with_options(CountingStore::default(), options).unwrap();
+            let page_id = manager.allocate_page().unwrap();
+            for _ in 0..3 {
+                manager.write_page(page_id, &SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap()).unwrap();
+            }
+            let before_sync = (manager.store.writes, manager.store.syncs);
+            manager.sync().unwrap();
+            (before_sync, manager.store.syncs)
+        };
+
+        // The header is written on create and on allocation, and it is synced on create
+        assert_eq!(write_and_sync(SyncPolicy::Never), ((5, 0), 0));
+        assert_eq!(write_and_sync(SyncPolicy::EveryWrite), ((5, 6), 7));
+        assert_eq!(write_and_sync(SyncPolicy::OnFlush), ((5, 1), 2));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let options = DiskOptions { sync_policy: SyncPolicy::Never, ..Default::default() };
+        let mut manager = DiskManager::with_options(CountingStore::default(), options.clone()).unwrap();
+        assert_eq!(manager.checkpoint_id(), 0);
+        let page_id = manager.allocate_page().unwrap();
+        manager.write_page(page_id, &SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap()).unwrap();
+
+        // Synced before and after the header is written, even if the sync policy never syncs
+        assert_eq!(manager.checkpoint().unwrap(), 1);
+        assert_eq!(manager.store.syncs, 2);
+        assert_eq!(manager.checkpoint().unwrap(), 2);
+
+        let manager = DiskManager::with_options(manager.into_store(), options).unwrap();
+        assert_eq!(manager.checkpoint_id(), 2);
+    }
+
+    #[test]
+    fn test_double_write() {
+        let options = DiskOptions { double_write_slots: 4, ..Default::default() };
+        let mut manager = DiskManager::with_options(MemoryStore::new(), options.clone()).unwrap();
+        let page_id = manager.allocate_page().unwrap();
+        assert_eq!(page_id, PageId(6));
+        // The area is not visible from outside
+        assert_eq!(manager.fetch_page(PageId(2)).is_err(), true);
+        assert_eq!(manager.free_page(PageId(2)).is_err(), true);
+
+        let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+        page.add_cell(0, b"key", b"value").unwrap();
+        assert_eq!(manager.write_page(page_id, &page).is_ok(), true);
+
+        // A crash tears the page in place
+        let mut store = manager.into_store();
+        let mut torn = page.to_bytes().to_vec();
+        torn[PAGE_SIZE / 2..].fill(0);
+        store.write_page(page_id, &torn).unwrap();
+
+        let mut manager = DiskManager::with_options(store, options).unwrap();
+        let mut fetched = manager.fetch_page(page_id).unwrap();
+        assert_eq!(fetched.valid(), true);
+        assert_eq!(fetched.cell_view(0).body(), b"keyvalue");
+    }
+
+    #[test]
+    fn test_double_write_torn_header() {
+        let options = DiskOptions { double_write_slots: 4, ..Default::default() };
+        let mut manager = DiskManager::with_options(MemoryStore::new(), options.clone()).unwrap();
+        assert_eq!(manager.set_root_page_id(PageId(9)).is_ok(), true);
+
+        let mut store = manager.into_store();
+        let mut torn = [0; PAGE_SIZE];
+        store.read_page(HEADER_PAGE_ID, &mut torn).unwrap();
+        torn[PAGE_SIZE / 2..].fill(0xff);
+        store.write_page(HEADER_PAGE_ID, &torn).unwrap();
+
+        // The options do not matter for an existing database
+        let manager = DiskManager::open(store).unwrap();
+        assert_eq!(manager.root_page_id(), &PageId(9));
+        assert_eq!(manager.double_write.is_some(), true);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let mut manager = DiskManager::open(MemoryStore::new()).unwrap();
+        assert_eq!(manager.root_page_id().is_valid(), false);
+        assert_eq!(manager.set_root_page_id(PageId(5)).is_ok(), true);
+
+        let manager = reopen(manager);
+        assert_eq!(manager.root_page_id(), &PageId(5));
+        assert_eq!(manager.next_page_id(), &PageId(1));
+    }
+
+    #[test]
+    fn test_page_sizes() {
+        for page_size in PAGE_SIZES {
+            let mut manager = DiskManager::open(MemoryStore::with_page_size(page_size).unwrap()).unwrap();
+            assert_eq!(manager.page_size(), page_size);
+            let page_id = manager.allocate_page().unwrap();
+            let mut page = SlottedPage::new(page_size, MAGIC_NUMBER_LEAF).unwrap();
+            let value = vec![0xab; page_size / 2];
+            page.add_cell(0, b"key", &value).unwrap();
+            assert_eq!(manager.write_page(page_id, &page).is_ok(), true);
+            // A page of another size is rejected
+            let other_size = if page_size == PAGE_SIZE { PAGE_SIZE * 2 } else { PAGE_SIZE };
+            let err = manager.write_page(page_id, &SlottedPage::new(other_size, MAGIC_NUMBER_LEAF).unwrap()).err();
+            assert_eq!(matches!(err.unwrap().downcast_ref::<DiskError>(), Some(DiskError::PageSizeMismatch(_))), true);
+
+            let mut manager = reopen(manager);
+            assert_eq!(manager.page_size(), page_size);
+            let fetched = manager.fetch_page(page_id).unwrap();
+            assert_eq!(fetched.page_size(), page_size);
+            assert_eq!(fetched.cell_view(0).body()[3..], value[..]);
+        }
+    }
+
+    #[test]
+    fn test_reject_invalid_header() {
+        let mut store = MemoryStore::new();
+        store.allocate().unwrap();
+        store.write_page(HEADER_PAGE_ID, &[0xff; PAGE_SIZE]).unwrap();
+        let ret = DiskManager::open(store);
+        assert_eq!(ret.is_err(), true);
+        let err = ret.err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::InvalidHeader(_))), true);
+    }
+}
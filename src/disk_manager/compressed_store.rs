@@ -0,0 +1,446 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use binary_layout::define_layout;
+
+use crate::btree::slotted_page::{is_valid_page_size, MAX_PAGE_SIZE, PAGE_SIZE};
+use crate::disk_manager::{check_buffer_size, DiskError, PageId, PageStore};
+use crate::disk_manager::file_store::lock_file;
+use crate::disk_manager::header::peek_page_size;
+
+/*
+ Pages are compressed with LZ4 and packed into the data file in units of sectors.
+ The page map file next to it ("<file>.map") has an entry per page telling where the page lives.
+ -------------------------------------------------------------------
+ |                 Offset in the data file (8b)                    |
+ -------------------------------------------------------------------
+ |     Stored length (4b)        |            Flags (4b)           |
+ -------------------------------------------------------------------
+ A stored length of 0 means the page has never been written and reads as zeros.
+
+ The data file is synced on every write before the page map points to the new data, whatever
+ the SyncPolicy of the DiskManager is. The policy only decides when the page map is synced.
+ */
+
+const SECTOR_SIZE: u64 = 512;
+const FLAG_UNCOMPRESSED: u32 = 1;
+
+define_layout!(map_entry, BigEndian, {
+    offset: u64,
+    length: u32,
+    flags: u32,
+});
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+struct MapEntry {
+    offset: u64,
+    length: u32,
+    flags: u32,
+}
+
+impl MapEntry {
+    fn capacity(&self) -> u64 {
+        (self.length as u64).div_ceil(SECTOR_SIZE) * SECTOR_SIZE
+    }
+}
+
+pub struct CompressedStore {
+    data: File,
+    map: File,
+    entries: Vec<MapEntry>,
+    // Unused ranges of the data file as (offset, length), sorted by the offset
+    free_extents: Vec<(u64, u64)>,
+    // Ranges released since the last sync. They are still referenced by the page map on disk,
+    // so they must not be overwritten until the new map is durable.
+    released_extents: Vec<(u64, u64)>,
+    data_end: u64,
+    page_size: usize,
+}
+
+impl CompressedStore {
+    pub fn open(file_path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_page_size(file_path, None)
+    }
+
+    // The page size applies to a new file, see FileOptions::page_size
+    pub fn with_page_size(file_path: impl AsRef<Path>, page_size: Option<usize>) -> Result<Self> {
+        if let Some(page_size) = page_size {
+            if !is_valid_page_size(page_size) {
+                return Err(DiskError::UnsupportedPageSize(page_size).into());
+            }
+        }
+        let file_path = file_path.as_ref();
+        let data = open_file(file_path)?;
+        lock_file(&data, file_path, false)?;
+        let mut map_path = file_path.as_os_str().to_os_string();
+        map_path.push(".map");
+        let map = open_file(&PathBuf::from(map_path))?;
+
+        let entry_size = map_entry::SIZE.unwrap() as u64;
+        let number_of_pages = map.metadata().context("failed to read the page map metadata")?.len() / entry_size;
+        let mut buf = vec![0; (number_of_pages * entry_size) as usize];
+        map.read_exact_at(&mut buf, 0).context("failed to read the page map")?;
+        let entries: Vec<MapEntry> = buf.chunks(entry_size as usize)
+            .map(|bytes| {
+                let view = map_entry::View::new(bytes);
+                MapEntry {
+                    offset: view.offset().read(),
+                    length: view.length().read(),
+                    flags: view.flags().read(),
+                }
+            })
+            .collect();
+
+        // Everything not referenced by the page map is free
+        let mut used: Vec<(u64, u64)> = entries.iter()
+            .filter(|entry| entry.length > 0)
+            .map(|entry| (entry.offset, entry.capacity()))
+            .collect();
+        used.sort();
+        let mut free_extents = vec![];
+        let mut data_end = 0;
+        for (offset, length) in used {
+            if offset > data_end {
+                free_extents.push((data_end, offset - data_end));
+            }
+            data_end = data_end.max(offset + length);
+        }
+
+        let mut store = Self {
+            data,
+            map,
+            entries,
+            free_extents,
+            released_extents: vec![],
+            data_end,
+            page_size: MAX_PAGE_SIZE,
+        };
+        // The header page tells the page size, which is the size of the page once decompressed
+        let mut head = vec![0; MAX_PAGE_SIZE];
+        let recorded = match store.entries.first() {
+            Some(entry) if entry.length > 0 => {
+                let length = store.read_stored(PageId(0), &mut head)?;
+                peek_page_size(&head[..length])
+            }
+            _ => None,
+        };
+        store.page_size = match (recorded, page_size) {
+            (Some(recorded), Some(requested)) if recorded != requested => return Err(DiskError::PageSizeMismatch(recorded as u32).into()),
+            (Some(recorded), _) => recorded,
+            (None, requested) => requested.unwrap_or(PAGE_SIZE),
+        };
+
+        Ok(store)
+    }
+
+    // Bytes of the data file in use, which is what compression saves on
+    pub fn compressed_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.length as u64).sum()
+    }
+
+    fn entry(&self, page_id: PageId) -> Result<MapEntry> {
+        self.entries.get(page_id.to_u32() as usize).copied().ok_or_else(|| DiskError::PageNotAllocated(page_id).into())
+    }
+
+    // First fit, and the data file grows when no free extent is large enough
+    fn reserve(&mut self, capacity: u64) -> u64 {
+        if let Some(index) = self.free_extents.iter().position(|&(_, length)| length >= capacity) {
+            let (offset, length) = self.free_extents[index];
+            if length == capacity {
+                self.free_extents.remove(index);
+            } else {
+                self.free_extents[index] = (offset + capacity, length - capacity);
+            }
+            return offset;
+        }
+        let offset = self.data_end;
+        self.data_end += capacity;
+        offset
+    }
+
+    fn release(&mut self, offset: u64, length: u64) {
+        let index = self.free_extents.partition_point(|&(o, _)| o < offset);
+        self.free_extents.insert(index, (offset, length));
+        // Merge with the neighbours
+        if index + 1 < self.free_extents.len() && offset + length == self.free_extents[index + 1].0 {
+            self.free_extents[index].1 += self.free_extents[index + 1].1;
+            self.free_extents.remove(index + 1);
+        }
+        if index > 0 && self.free_extents[index - 1].0 + self.free_extents[index - 1].1 == offset {
+            self.free_extents[index - 1].1 += self.free_extents[index].1;
+            self.free_extents.remove(index);
+        }
+    }
+
+    // Decompresses the page into the buffer and returns the size of the page
+    fn read_stored(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<usize> {
+        let entry = self.entry(page_id)?;
+        if entry.length == 0 {
+            buf.fill(0);
+            return Ok(self.page_size);
+        }
+        let mut stored = vec![0; entry.length as usize];
+        self.data.read_exact_at(&mut stored, entry.offset).context("failed to read bytes from the file")?;
+        if entry.flags & FLAG_UNCOMPRESSED != 0 {
+            let length = stored.len().min(buf.len());
+            buf[..length].copy_from_slice(&stored[..length]);
+            return Ok(stored.len());
+        }
+        lz4_flex::block::decompress_into(&stored, buf)
+            .map_err(|e| anyhow!("failed to decompress the page {:?}: {}", page_id, e))
+    }
+
+    // Writes the page into a new extent, which is not referenced by the page map yet
+    fn write_data(&mut self, page_id: PageId, buf: &[u8]) -> Result<MapEntry> {
+        check_buffer_size(buf, self.page_size)?;
+        self.entry(page_id)?;
+        let compressed = lz4_flex::block::compress(buf);
+        let (stored, flags) = if compressed.len() < buf.len() {
+            (&compressed[..], 0)
+        } else {
+            (buf, FLAG_UNCOMPRESSED)
+        };
+        let mut entry = MapEntry {
+            offset: 0,
+            length: stored.len() as u32,
+            flags,
+        };
+        entry.offset = self.reserve(entry.capacity());
+        if let Err(err) = self.data.write_all_at(stored, entry.offset) {
+            self.release(entry.offset, entry.capacity());
+            return Err(anyhow!(err).context("failed to write bytes into the file"));
+        }
+
+        Ok(entry)
+    }
+
+    // The extents are given back when they cannot be made durable
+    fn sync_data(&mut self, entries: &[MapEntry]) -> Result<()> {
+        if let Err(err) = self.data.sync_data() {
+            for entry in entries {
+                self.release(entry.offset, entry.capacity());
+            }
+            return Err(anyhow!(err).context("failed to sync the file"));
+        }
+        Ok(())
+    }
+
+    // Points the page map to the new extent, and the old one is reused after the next sync.
+    // When the page map cannot be written, the page map on disk may point to either extent,
+    // so neither is reused.
+    fn commit(&mut self, page_id: PageId, entry: MapEntry) -> Result<()> {
+        let old = self.entry(page_id)?;
+        self.write_entry(page_id, entry)?;
+        self.entries[page_id.to_u32() as usize] = entry;
+        if old.length > 0 {
+            self.released_extents.push((old.offset, old.capacity()));
+        }
+        Ok(())
+    }
+
+    fn write_entry(&mut self, page_id: PageId, entry: MapEntry) -> Result<()> {
+        let mut buf = [0; 16];
+        let mut view = map_entry::View::new(&mut buf[..]);
+        view.offset_mut().write(entry.offset);
+        view.length_mut().write(entry.length);
+        view.flags_mut().write(entry.flags);
+        let offset = page_id.to_u64() * buf.len() as u64;
+        self.map.write_all_at(&buf, offset).context("failed to write the page map")
+    }
+}
+
+fn open_file(file_path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(file_path)
+        .with_context(|| format!("failed to open file, file_path: {:?}", file_path))
+}
+
+impl PageStore for CompressedStore {
+    fn read_page(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()> {
+        check_buffer_size(buf, self.page_size)?;
+        let length = self.read_stored(page_id, buf)?;
+        if length != self.page_size {
+            return Err(anyhow!("failed to decompress the page {:?}: {} bytes", page_id, length));
+        }
+
+        Ok(())
+    }
+
+    // Pages are never overwritten in place, and the data is synced before the page map points to it,
+    // so the page map on disk always points to a complete page
+    fn write_page(&mut self, page_id: PageId, buf: &[u8]) -> Result<()> {
+        let entry = self.write_data(page_id, buf)?;
+        self.sync_data(&[entry])?;
+        self.commit(page_id, entry)
+    }
+
+    // Same as write_page(), but a batch costs a single sync
+    fn write_pages(&mut self, requests: &[(PageId, &[u8])]) -> Result<()> {
+        let mut failures = vec![];
+        let mut written = vec![];
+        for &(page_id, buf) in requests {
+            match self.write_data(page_id, buf) {
+                Ok(entry) => written.push((page_id, entry)),
+                Err(err) => failures.push((page_id, err)),
+            }
+        }
+        if !written.is_empty() {
+            let entries: Vec<MapEntry> = written.iter().map(|(_, entry)| *entry).collect();
+            self.sync_data(&entries)?;
+        }
+        for (page_id, entry) in written {
+            if let Err(err) = self.commit(page_id, entry) {
+                failures.push((page_id, err));
+            }
+        }
+        if !failures.is_empty() {
+            return Err(DiskError::PartialWrite(failures).into());
+        }
+
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<PageId> {
+        // Recorded in memory only once it is in the page map, so a failure leaves the length as it was
+        let page_id = PageId(self.entries.len() as u32);
+        self.write_entry(page_id, MapEntry::default())?;
+        self.entries.push(MapEntry::default());
+
+        Ok(page_id)
+    }
+
+    // The extents of the discarded pages are reused after the next sync like overwritten ones
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        if len >= self.len() {
+            return Ok(());
+        }
+        let discarded: Vec<MapEntry> = self.entries.drain(len as usize..).collect();
+        self.map.set_len(len * map_entry::SIZE.unwrap() as u64).context("failed to truncate the page map")?;
+        for entry in discarded.into_iter().filter(|entry| entry.length > 0) {
+            self.released_extents.push((entry.offset, entry.capacity()));
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.data.sync_data().context("failed to sync the file")?;
+        self.map.sync_data().context("failed to sync the page map")?;
+        for (offset, length) in std::mem::take(&mut self.released_extents) {
+            self.release(offset, length);
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, SlottedPage};
+
+    use super::*;
+
+    fn page_with_cells(number_of_cells: usize) -> SlottedPage {
+        let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+        for i in 0..number_of_cells {
+            page.add_cell(i, &(i as u32).to_be_bytes(), &[i as u8; 16]).unwrap();
+        }
+        page
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        let number_of_pages = 100;
+
+        let mut store = CompressedStore::open(&path).unwrap();
+        for i in 0..number_of_pages {
+            assert_eq!(store.allocate().unwrap(), PageId(i));
+            assert_eq!(store.write_page(PageId(i), page_with_cells(i as usize % 5).to_bytes()).is_ok(), true);
+        }
+        // Random bytes do not compress, and they are stored as they are
+        let mut noise = [0; PAGE_SIZE];
+        let mut x: u32 = 12345;
+        for b in noise.iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        let noise_page_id = store.allocate().unwrap();
+        assert_eq!(store.write_page(noise_page_id, &noise).is_ok(), true);
+        let unwritten_page_id = store.allocate().unwrap();
+        assert_eq!(store.sync().is_ok(), true);
+        drop(store);
+
+        let data_size = fs::metadata(&path).unwrap().len();
+        assert_eq!(data_size < (number_of_pages as u64 + 1) * PAGE_SIZE as u64 / 4, true);
+
+        let mut store = CompressedStore::open(&path).unwrap();
+        assert_eq!(store.len(), number_of_pages as u64 + 2);
+        let mut page = SlottedPage::zeroed(PAGE_SIZE).unwrap();
+        for i in 0..number_of_pages {
+            assert_eq!(store.read_page(PageId(i), page.to_bytes_mut()).is_ok(), true);
+            assert_eq!(page.to_bytes(), page_with_cells(i as usize % 5).to_bytes());
+        }
+        let mut buf = [0xff; PAGE_SIZE];
+        store.read_page(noise_page_id, &mut buf).unwrap();
+        assert_eq!(buf, noise);
+        store.read_page(unwritten_page_id, &mut buf).unwrap();
+        assert_eq!(buf, [0; PAGE_SIZE]);
+        let err = store.read_page(PageId(number_of_pages + 2), &mut buf).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(_))), true);
+    }
+
+    #[test]
+    fn test_reuse_space_after_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+
+        let mut store = CompressedStore::open(&path).unwrap();
+        let page_id = store.allocate().unwrap();
+        let page = page_with_cells(3);
+        store.write_page(page_id, page.to_bytes()).unwrap();
+        let first = store.entries[0];
+
+        // The old location is kept until the new page map is synced
+        store.write_page(page_id, page.to_bytes()).unwrap();
+        let second = store.entries[0];
+        assert_eq!(second.offset > first.offset, true);
+        store.sync().unwrap();
+        store.write_page(page_id, page.to_bytes()).unwrap();
+        assert_eq!(store.entries[0].offset, first.offset);
+        assert_eq!(store.compressed_size(), first.length as u64);
+    }
+
+    #[test]
+    fn test_allocate_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+
+        let mut store = CompressedStore::open(&path).unwrap();
+        assert_eq!(store.allocate().unwrap(), PageId(0));
+        // The page map cannot be written through a read-only handle
+        let map = std::mem::replace(&mut store.map, File::open(dir.path().join("test.idb.map")).unwrap());
+        assert_eq!(store.allocate().is_err(), true);
+        assert_eq!(store.len(), 1);
+        store.map = map;
+        assert_eq!(store.allocate().unwrap(), PageId(1));
+    }
+}
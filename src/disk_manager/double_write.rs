@@ -0,0 +1,240 @@
+use anyhow::{Context, Result};
+use binary_layout::define_layout;
+
+use crate::btree::slotted_page::{body_size, MAGIC_NUMBER_DOUBLE_WRITE, SlottedPage};
+use crate::disk_manager::{DiskError, PageId, PageStore};
+
+/*
+ The double-write area follows the file header when it is enabled on create:
+ the directory page at PageId(1) and then one slot page per page in a batch.
+ Pages are written and synced into the slots first, and then written in place,
+ so a page torn by a crash can be restored from its copy on the next open.
+
+ Directory page body
+ -------------------------------------------------------------------
+ |                      Number of slots (4b)                       |
+ -------------------------------------------------------------------
+ |                 Number of pages in the batch (4b)               |
+ -------------------------------------------------------------------
+ |               Page ids of the copies in the slots (4b each)     |
+ -------------------------------------------------------------------
+ */
+
+pub const DOUBLE_WRITE_PAGE_ID: PageId = PageId(1);
+const DIRECTORY_HEADER_SIZE: usize = 8;
+
+// The directory page lists one page id per slot
+pub fn max_double_write_slots(page_size: usize) -> usize {
+    (body_size(page_size) - DIRECTORY_HEADER_SIZE) / 4
+}
+
+define_layout!(directory, BigEndian, {
+    number_of_slots: u32,
+    number_of_pages: u32,
+    page_ids: [u8],
+});
+
+pub struct DoubleWriteBuffer {
+    number_of_slots: usize,
+    // Pages written in place since the last sync, which must be durable before their copies are overwritten
+    unsynced: bool,
+}
+
+impl DoubleWriteBuffer {
+    // Reserves the area right after the header page of an empty store
+    pub fn create(store: &mut impl PageStore, number_of_slots: usize) -> Result<Self> {
+        if number_of_slots == 0 || number_of_slots > max_double_write_slots(store.page_size()) {
+            return Err(DiskError::InvalidHeader(format!("invalid number of double-write slots: {}", number_of_slots)).into());
+        }
+        for _ in 0..=number_of_slots {
+            store.allocate().context("failed to allocate the double-write area")?;
+        }
+        let buffer = Self {
+            number_of_slots,
+            unsynced: false,
+        };
+        buffer.write_directory(store, &[])?;
+
+        Ok(buffer)
+    }
+
+    // Returns None when the store has no double-write area
+    pub fn load(store: &mut impl PageStore) -> Result<Option<Self>> {
+        if store.len() <= DOUBLE_WRITE_PAGE_ID.to_u64() {
+            return Ok(None);
+        }
+        let mut page = SlottedPage::zeroed(store.page_size())?;
+        store.read_page(DOUBLE_WRITE_PAGE_ID, page.to_bytes_mut()).context("failed to read the double-write directory")?;
+        if page.header_view().magic_number().read() != MAGIC_NUMBER_DOUBLE_WRITE || !page.verify_check_sum() {
+            return Ok(None);
+        }
+        let number_of_slots = directory::View::new(page.body_view()).number_of_slots().read() as usize;
+
+        Ok(Some(Self {
+            number_of_slots,
+            unsynced: false,
+        }))
+    }
+
+    pub fn number_of_slots(&self) -> usize {
+        self.number_of_slots
+    }
+
+    // The first page id after the area
+    pub fn end_page_id(&self) -> PageId {
+        PageId(DOUBLE_WRITE_PAGE_ID.to_u32() + self.number_of_slots as u32 + 1)
+    }
+
+    pub fn contains(&self, page_id: PageId) -> bool {
+        page_id.to_u32() >= DOUBLE_WRITE_PAGE_ID.to_u32() && page_id.to_u32() < self.end_page_id().to_u32()
+    }
+
+    // The store must be synced after this for the in-place writes to be durable
+    pub fn write_pages(&mut self, store: &mut impl PageStore, requests: &[(PageId, &[u8])]) -> Result<()> {
+        let mut failures = vec![];
+        for batch in requests.chunks(self.number_of_slots) {
+            if self.unsynced {
+                store.sync().context("failed to sync pages written in place")?;
+                self.unsynced = false;
+            }
+            let copies: Vec<(PageId, &[u8])> = batch.iter().enumerate()
+                .map(|(i, (_, buf))| (self.slot_page_id(i), *buf))
+                .collect();
+            store.write_pages(&copies).context("failed to write pages into the double-write area")?;
+            let page_ids: Vec<PageId> = batch.iter().map(|(page_id, _)| *page_id).collect();
+            self.write_directory(store, &page_ids)?;
+            store.sync().context("failed to sync the double-write area")?;
+
+            self.unsynced = true;
+            if let Err(err) = store.write_pages(batch) {
+                match err.downcast::<DiskError>() {
+                    Ok(DiskError::PartialWrite(batch_failures)) => failures.extend(batch_failures),
+                    Ok(err) => return Err(err.into()),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        if !failures.is_empty() {
+            return Err(DiskError::PartialWrite(failures).into());
+        }
+
+        Ok(())
+    }
+
+    pub fn mark_synced(&mut self) {
+        self.unsynced = false;
+    }
+
+    // Restores the pages of the last batch which are broken in place, and returns their ids
+    pub fn recover(&mut self, store: &mut impl PageStore) -> Result<Vec<PageId>> {
+        let mut page = SlottedPage::zeroed(store.page_size())?;
+        store.read_page(DOUBLE_WRITE_PAGE_ID, page.to_bytes_mut()).context("failed to read the double-write directory")?;
+        let view = directory::View::new(page.body_view());
+        let number_of_pages = (view.number_of_pages().read() as usize).min(self.number_of_slots);
+        let page_ids: Vec<PageId> = view.page_ids()[..number_of_pages * 4].chunks(4)
+            .map(|bytes| PageId(u32::from_be_bytes(bytes.try_into().unwrap())))
+            .collect();
+
+        let mut restored = vec![];
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            if page_id.to_u64() >= store.len() {
+                continue;
+            }
+            // A store below may reject a torn page on read, e.g. EncryptedStore fails to authenticate it
+            let mut in_place = SlottedPage::zeroed(store.page_size())?;
+            let intact = store.read_page(page_id, in_place.to_bytes_mut()).is_ok()
+                && in_place.verify_check_sum()
+                && in_place.header_view().page_id().read() == page_id.to_u32();
+            if intact {
+                continue;
+            }
+            let mut copy = SlottedPage::zeroed(store.page_size())?;
+            // The copy may be torn as well when the crash happened before the directory was updated
+            let restorable = store.read_page(self.slot_page_id(i), copy.to_bytes_mut()).is_ok()
+                && copy.verify_check_sum()
+                && copy.header_view().page_id().read() == page_id.to_u32();
+            if !restorable {
+                continue;
+            }
+            store.write_page(page_id, copy.to_bytes()).with_context(|| format!("failed to restore the page {:?}", page_id))?;
+            restored.push(page_id);
+        }
+        if !restored.is_empty() {
+            store.sync().context("failed to sync restored pages")?;
+        }
+
+        Ok(restored)
+    }
+
+    fn slot_page_id(&self, slot: usize) -> PageId {
+        PageId(DOUBLE_WRITE_PAGE_ID.to_u32() + 1 + slot as u32)
+    }
+
+    fn write_directory(&self, store: &mut impl PageStore, page_ids: &[PageId]) -> Result<()> {
+        let mut page = SlottedPage::new(store.page_size(), MAGIC_NUMBER_DOUBLE_WRITE)?;
+        let mut view = directory::View::new(page.body_view_mut());
+        view.number_of_slots_mut().write(self.number_of_slots as u32);
+        view.number_of_pages_mut().write(page_ids.len() as u32);
+        for (i, page_id) in page_ids.iter().enumerate() {
+            view.page_ids_mut()[i * 4..(i + 1) * 4].copy_from_slice(&page_id.to_u32().to_be_bytes());
+        }
+        let sum = page.check_sum();
+        page.header_view_mut().check_sum_mut().write(sum);
+
+        store.write_page(DOUBLE_WRITE_PAGE_ID, page.to_bytes()).context("failed to write the double-write directory")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, PAGE_SIZE};
+    use crate::disk_manager::memory_store::MemoryStore;
+
+    use super::*;
+
+    #[test]
+    fn test_recover() {
+        let mut store = MemoryStore::new();
+        store.allocate().unwrap();
+        let mut buffer = DoubleWriteBuffer::create(&mut store, 2).unwrap();
+        assert_eq!(buffer.end_page_id(), PageId(4));
+        let page_ids: Vec<PageId> = (0..3).map(|_| store.allocate().unwrap()).collect();
+
+        let mut pages = vec![];
+        for i in 0..3_u8 {
+            let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+            page.add_cell(0, &[i], b"value").unwrap();
+            page.header_view_mut().page_id_mut().write(page_ids[i as usize].to_u32());
+            let sum = page.check_sum();
+            page.header_view_mut().check_sum_mut().write(sum);
+            pages.push(page);
+        }
+        let requests: Vec<(PageId, &[u8])> = page_ids.iter().copied().zip(pages.iter().map(|p| p.to_bytes())).collect();
+        assert_eq!(buffer.write_pages(&mut store, &requests).is_ok(), true);
+
+        // Tear the pages of the last batch and the first batch
+        let mut torn = pages[2].to_bytes().to_vec();
+        torn[PAGE_SIZE / 2..].fill(0);
+        store.write_page(page_ids[2], &torn).unwrap();
+        store.write_page(page_ids[0], &torn).unwrap();
+
+        let mut buffer = DoubleWriteBuffer::load(&mut store).unwrap().unwrap();
+        assert_eq!(buffer.number_of_slots(), 2);
+        // Only the last batch is still in the area
+        assert_eq!(buffer.recover(&mut store).unwrap(), vec![page_ids[2]]);
+        let mut page = SlottedPage::zeroed(store.page_size()).unwrap();
+        store.read_page(page_ids[2], page.to_bytes_mut()).unwrap();
+        assert_eq!(page.valid(), true);
+        assert_eq!(page.cell_view(0).body()[0], 2);
+        assert_eq!(buffer.recover(&mut store).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_load_without_area() {
+        let mut store = MemoryStore::new();
+        store.allocate().unwrap();
+        assert_eq!(DoubleWriteBuffer::load(&mut store).unwrap().is_none(), true);
+        store.allocate().unwrap();
+        assert_eq!(DoubleWriteBuffer::load(&mut store).unwrap().is_none(), true);
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce, Tag};
+use anyhow::Result;
+use binary_layout::define_layout;
+
+use crate::btree::slotted_page::RESERVED_SIZE;
+use crate::disk_manager::header::HEADER_PAGE_ID;
+use crate::disk_manager::{check_buffer_size, DiskError, PageId, PageStore};
+
+/*
+ Pages are encrypted with AES-256-GCM right above the store, so everything DiskManager writes,
+ including the free list and the copies in the double-write area, is encrypted.
+ This is below DiskManager rather than between it and BufferManager, so the file header page stays
+ in plain text: the stores below read the page size from it before they can read any page.
+ It is authenticated instead, with a tag over its body in the same trailer as the other pages,
+ so that a modified root page id or free list head fails to open.
+
+ The tag and the nonce are kept in the reserved bytes at the end of each page
+ -------------------------------------------------------------------
+ |                           Key id (4b)                           |
+ -------------------------------------------------------------------
+ |                        Write counter (8b)                       |
+ -------------------------------------------------------------------
+ |                            Tag (16b)                            |
+ -------------------------------------------------------------------
+ |                          Padding (4b)                           |
+ -------------------------------------------------------------------
+ The nonce is the page id followed by the write counter. The upper half of the counter is an epoch,
+ which is bumped and recorded in the header page on every open, so a nonce is never reused after a restart.
+ The header page keeps the id of the current key and the epoch in its reserved bytes.
+ */
+
+pub const KEY_SIZE: usize = 32;
+
+define_layout!(page_trailer, BigEndian, {
+    key_id: u32,
+    counter: u64,
+    tag: [u8; 16],
+});
+
+// Where the keys come from, e.g. a KMS. Key ids are never 0, which means the page is not encrypted.
+// Old keys have to stay available as long as pages encrypted with them remain in the file.
+// The store is shared with background threads, e.g. the writer of the access manager.
+pub trait KeyProvider: Send + Sync {
+    // The key new pages are encrypted with
+    fn current_key_id(&self) -> u32;
+    fn key(&self, key_id: u32) -> Option<[u8; KEY_SIZE]>;
+}
+
+pub struct EncryptedStore<S: PageStore> {
+    store: S,
+    key_provider: Box<dyn KeyProvider>,
+    ciphers: HashMap<u32, Aes256Gcm>,
+    epoch: u32,
+    sequence: u32,
+}
+
+impl<S: PageStore> EncryptedStore<S> {
+    pub fn open(store: S, key_provider: Box<dyn KeyProvider>) -> Result<Self> {
+        let mut encrypted = Self {
+            store,
+            key_provider,
+            ciphers: HashMap::new(),
+            epoch: 1,
+            sequence: 0,
+        };
+        if encrypted.store.len() > 0 {
+            let mut buf = vec![0; encrypted.store.page_size()];
+            encrypted.store.read_page(HEADER_PAGE_ID, &mut buf)?;
+            let trailer = page_trailer::View::new(trailer(&buf));
+            if trailer.key_id().read() == 0 {
+                return Err(DiskError::InvalidHeader("the database is not encrypted".to_string()).into());
+            }
+            let epoch = (trailer.counter().read() >> 32) as u32;
+            encrypted.verify_header_page(&mut buf)?;
+            encrypted.epoch = epoch + 1;
+            encrypted.write_header_page(&mut buf)?;
+            encrypted.store.sync()?;
+        }
+
+        Ok(encrypted)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    fn cipher(&mut self, key_id: u32) -> Result<&Aes256Gcm> {
+        if !self.ciphers.contains_key(&key_id) {
+            let key = self.key_provider.key(key_id).ok_or(DiskError::UnknownKey(key_id))?;
+            self.ciphers.insert(key_id, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)));
+        }
+        Ok(&self.ciphers[&key_id])
+    }
+
+    fn next_counter(&mut self) -> Result<u64> {
+        if self.sequence == u32::MAX {
+            // Move on to a new epoch before the counter wraps around
+            let mut buf = vec![0; self.store.page_size()];
+            self.store.read_page(HEADER_PAGE_ID, &mut buf)?;
+            self.epoch += 1;
+            self.sequence = 0;
+            self.write_header_page(&mut buf)?;
+            self.store.sync()?;
+        }
+        self.sequence += 1;
+        Ok(((self.epoch as u64) << 32) | self.sequence as u64)
+    }
+
+    // The header page is not encrypted, but its body is authenticated with the current key.
+    // The counter of the tag carries the epoch for the next open.
+    fn write_header_page(&mut self, buf: &mut [u8]) -> Result<()> {
+        let key_id = self.key_provider.current_key_id();
+        let counter = self.next_counter()?;
+        let (body, trailer) = split_trailer(buf);
+        let tag = self.cipher(key_id)?
+            .encrypt_in_place_detached(&nonce(HEADER_PAGE_ID, counter), &header_aad(key_id, body), &mut [])
+            .map_err(|_| anyhow::anyhow!("failed to authenticate the file header"))?;
+        let mut trailer = page_trailer::View::new(trailer);
+        trailer.key_id_mut().write(key_id);
+        trailer.counter_mut().write(counter);
+        trailer.tag_mut().copy_from_slice(&tag);
+        self.store.write_page(HEADER_PAGE_ID, buf)
+    }
+
+    fn verify_header_page(&mut self, buf: &mut [u8]) -> Result<()> {
+        let trailer = page_trailer::View::new(trailer(buf));
+        let key_id = trailer.key_id().read();
+        let counter = trailer.counter().read();
+        let tag = *Tag::from_slice(trailer.tag());
+        let (body, trailer) = split_trailer(buf);
+        self.cipher(key_id)?
+            .decrypt_in_place_detached(&nonce(HEADER_PAGE_ID, counter), &header_aad(key_id, body), &mut [], &tag)
+            .map_err(|_| DiskError::AuthenticationFailed(HEADER_PAGE_ID))?;
+        trailer.fill(0);
+
+        Ok(())
+    }
+
+    fn encrypt(&mut self, page_id: PageId, buf: &[u8]) -> Result<Box<[u8]>> {
+        check_buffer_size(buf, self.store.page_size())?;
+        let key_id = self.key_provider.current_key_id();
+        let counter = self.next_counter()?;
+        let mut encrypted = buf.to_vec().into_boxed_slice();
+        let (body, trailer) = split_trailer(&mut encrypted);
+        let tag = self.cipher(key_id)?
+            .encrypt_in_place_detached(&nonce(page_id, counter), &aad(page_id, key_id), body)
+            .map_err(|_| anyhow::anyhow!("failed to encrypt the page {:?}", page_id))?;
+        let mut trailer = page_trailer::View::new(trailer);
+        trailer.key_id_mut().write(key_id);
+        trailer.counter_mut().write(counter);
+        trailer.tag_mut().copy_from_slice(&tag);
+
+        Ok(encrypted)
+    }
+
+    fn decrypt(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()> {
+        let trailer = page_trailer::View::new(trailer(buf));
+        let key_id = trailer.key_id().read();
+        let counter = trailer.counter().read();
+        let tag = *Tag::from_slice(trailer.tag());
+        if key_id == 0 {
+            // A page allocated but never written is all zeros
+            if buf.iter().all(|&b| b == 0) {
+                return Ok(());
+            }
+            return Err(DiskError::AuthenticationFailed(page_id).into());
+        }
+        let (body, trailer) = split_trailer(buf);
+        self.cipher(key_id)?
+            .decrypt_in_place_detached(&nonce(page_id, counter), &aad(page_id, key_id), body, &tag)
+            .map_err(|_| DiskError::AuthenticationFailed(page_id))?;
+        trailer.fill(0);
+
+        Ok(())
+    }
+}
+
+// The tag and the nonce are kept in the reserved bytes, which are not encrypted
+fn trailer(buf: &[u8]) -> &[u8] {
+    &buf[buf.len() - RESERVED_SIZE..]
+}
+
+fn split_trailer(buf: &mut [u8]) -> (&mut [u8], &mut [u8]) {
+    let at = buf.len() - RESERVED_SIZE;
+    buf.split_at_mut(at)
+}
+
+fn nonce(page_id: PageId, counter: u64) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0; 12];
+    nonce[..4].copy_from_slice(&page_id.to_u32().to_be_bytes());
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+// Binding the page id prevents a page from being swapped with another one
+fn aad(page_id: PageId, key_id: u32) -> [u8; 8] {
+    let mut aad = [0; 8];
+    aad[..4].copy_from_slice(&page_id.to_u32().to_be_bytes());
+    aad[4..].copy_from_slice(&key_id.to_be_bytes());
+    aad
+}
+
+// The body of the header page is authenticated as it is, without being encrypted
+fn header_aad(key_id: u32, body: &[u8]) -> Vec<u8> {
+    let mut aad = aad(HEADER_PAGE_ID, key_id).to_vec();
+    aad.extend_from_slice(body);
+    aad
+}
+
+impl<S: PageStore> PageStore for EncryptedStore<S> {
+    fn read_page(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()> {
+        self.store.read_page(page_id, buf)?;
+        if page_id == HEADER_PAGE_ID {
+            return self.verify_header_page(buf);
+        }
+        self.decrypt(page_id, buf)
+    }
+
+    fn write_page(&mut self, page_id: PageId, buf: &[u8]) -> Result<()> {
+        if page_id == HEADER_PAGE_ID {
+            check_buffer_size(buf, self.store.page_size())?;
+            let mut buf = buf.to_vec();
+            return self.write_header_page(&mut buf);
+        }
+        let encrypted = self.encrypt(page_id, buf)?;
+        self.store.write_page(page_id, &encrypted)
+    }
+
+    fn read_pages(&mut self, requests: &mut [(PageId, &mut [u8])]) -> Result<()> {
+        self.store.read_pages(requests)?;
+        for (page_id, buf) in requests.iter_mut() {
+            if *page_id == HEADER_PAGE_ID {
+                self.verify_header_page(buf)?;
+                continue;
+            }
+            self.decrypt(*page_id, buf)?;
+        }
+        Ok(())
+    }
+
+    fn write_pages(&mut self, requests: &[(PageId, &[u8])]) -> Result<()> {
+        let mut encrypted = Vec::with_capacity(requests.len());
+        for &(page_id, buf) in requests {
+            match page_id {
+                HEADER_PAGE_ID => self.write_page(page_id, buf)?,
+                _ => encrypted.push((page_id, self.encrypt(page_id, buf)?)),
+            }
+        }
+        let requests: Vec<(PageId, &[u8])> = encrypted.iter().map(|(page_id, buf)| (*page_id, &**buf)).collect();
+        self.store.write_pages(&requests)
+    }
+
+    fn allocate(&mut self) -> Result<PageId> {
+        self.store.allocate()
+    }
+
+    fn allocate_pages(&mut self, count: usize) -> Result<PageId> {
+        self.store.allocate_pages(count)
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        self.store.truncate(len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.store.sync()
+    }
+
+    fn len(&self) -> u64 {
+        self.store.len()
+    }
+
+    fn page_size(&self) -> usize {
+        self.store.page_size()
+    }
+
+    fn pages_per_segment(&self) -> u32 {
+        self.store.pages_per_segment()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::thread;
+
+    use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, PAGE_SIZE, SlottedPage};
+    use crate::disk_manager::{DiskManager, DiskOptions};
+    use crate::disk_manager::header::FileHeader;
+    use crate::disk_manager::memory_store::MemoryStore;
+
+    use super::*;
+
+    struct TestKeyProvider {
+        current_key_id: Arc<AtomicU32>,
+        key_ids: Vec<u32>,
+    }
+
+    impl KeyProvider for TestKeyProvider {
+        fn current_key_id(&self) -> u32 {
+            self.current_key_id.load(Ordering::Acquire)
+        }
+
+        fn key(&self, key_id: u32) -> Option<[u8; KEY_SIZE]> {
+            self.key_ids.contains(&key_id).then_some([key_id as u8; KEY_SIZE])
+        }
+    }
+
+    fn key_provider(key_id: u32) -> Box<TestKeyProvider> {
+        Box::new(TestKeyProvider { current_key_id: Arc::new(AtomicU32::new(key_id)), key_ids: vec![key_id] })
+    }
+
+    fn test_page() -> SlottedPage {
+        let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+        page.add_cell(0, b"secret-key", b"secret-value").unwrap();
+        page
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn test_read_write() {
+        let store = EncryptedStore::open(MemoryStore::new(), key_provider(1)).unwrap();
+        let mut disk_manager = DiskManager::open(store).unwrap();
+        let page_id = disk_manager.allocate_page().unwrap();
+        disk_manager.write_page(page_id, &test_page()).unwrap();
+        disk_manager.set_root_page_id(page_id).unwrap();
+
+        let mut store = disk_manager.into_store().into_inner();
+        let mut raw = vec![0; store.page_size()];
+        store.read_page(page_id, &mut raw).unwrap();
+        assert_eq!(contains(&raw, b"secret-value"), false);
+        let first_counter = page_trailer::View::new(trailer(&raw)).counter().read();
+
+        // The header page is readable as is, and it records the key id
+        store.read_page(HEADER_PAGE_ID, &mut raw).unwrap();
+        assert_eq!(page_trailer::View::new(trailer(&raw)).key_id().read(), 1);
+
+        let store = EncryptedStore::open(store, key_provider(1)).unwrap();
+        let mut disk_manager = DiskManager::open(store).unwrap();
+        assert_eq!(*disk_manager.root_page_id(), page_id);
+        let mut page = disk_manager.fetch_page(page_id).unwrap();
+        assert_eq!(page.valid(), true);
+        assert_eq!(page.body_view(), test_page().body_view());
+
+        // The same page is never encrypted with the same nonce again after a restart
+        disk_manager.write_page(page_id, &test_page()).unwrap();
+        let mut store = disk_manager.into_store().into_inner();
+        store.read_page(page_id, &mut raw).unwrap();
+        let second_counter = page_trailer::View::new(trailer(&raw)).counter().read();
+        assert_eq!(second_counter >> 32 > first_counter >> 32, true);
+    }
+
+    #[test]
+    fn test_authentication_failure() {
+        let store = EncryptedStore::open(MemoryStore::new(), key_provider(1)).unwrap();
+        let mut disk_manager = DiskManager::open(store).unwrap();
+        let page_id = disk_manager.allocate_page().unwrap();
+        disk_manager.write_page(page_id, &test_page()).unwrap();
+        let mut store = disk_manager.into_store().into_inner();
+
+        let mut raw = vec![0; store.page_size()];
+        store.read_page(page_id, &mut raw).unwrap();
+        raw[100] ^= 1;
+        store.write_page(page_id, &raw).unwrap();
+
+        let store = EncryptedStore::open(store, key_provider(1)).unwrap();
+        let mut disk_manager = DiskManager::open(store).unwrap();
+        let err = disk_manager.fetch_page(page_id).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::AuthenticationFailed(id)) if *id == page_id), true);
+
+        // A database whose key is not available cannot be opened
+        let store = disk_manager.into_store().into_inner();
+        let ret = EncryptedStore::open(store, key_provider(3));
+        assert_eq!(matches!(ret.err().unwrap().downcast_ref::<DiskError>(), Some(DiskError::UnknownKey(1))), true);
+
+        // Nor can a plain text database
+        let mut disk_manager = DiskManager::open(MemoryStore::new()).unwrap();
+        disk_manager.allocate_page().unwrap();
+        let ret = EncryptedStore::open(disk_manager.into_store(), key_provider(1));
+        assert_eq!(matches!(ret.err().unwrap().downcast_ref::<DiskError>(), Some(DiskError::InvalidHeader(_))), true);
+
+        // The header page is in plain text, but a modified root page id is detected
+        let store = EncryptedStore::open(MemoryStore::new(), key_provider(1)).unwrap();
+        let mut disk_manager = DiskManager::open(store).unwrap();
+        let page_id = disk_manager.allocate_page().unwrap();
+        disk_manager.set_root_page_id(page_id).unwrap();
+        let mut store = disk_manager.into_store().into_inner();
+        let mut raw = vec![0; store.page_size()];
+        store.read_page(HEADER_PAGE_ID, &mut raw).unwrap();
+        let mut header = FileHeader::from_page(&mut SlottedPage::from_bytes(&raw).unwrap()).unwrap();
+        header.root_page_id = PageId(7);
+        let mut tampered = header.to_page().unwrap().to_bytes().to_vec();
+        split_trailer(&mut tampered).1.copy_from_slice(trailer(&raw));
+        store.write_page(HEADER_PAGE_ID, &tampered).unwrap();
+        let ret = EncryptedStore::open(store, key_provider(1));
+        assert_eq!(matches!(ret.err().unwrap().downcast_ref::<DiskError>(), Some(DiskError::AuthenticationFailed(HEADER_PAGE_ID))), true);
+    }
+
+    #[test]
+    fn test_double_write_torn_page() {
+        let options = DiskOptions { double_write_slots: 4, ..Default::default() };
+        let store = EncryptedStore::open(MemoryStore::new(), key_provider(1)).unwrap();
+        let mut disk_manager = DiskManager::with_options(store, options.clone()).unwrap();
+        let page_id = disk_manager.allocate_page().unwrap();
+        disk_manager.write_page(page_id, &test_page()).unwrap();
+
+        // The page torn in place fails to authenticate, and its copy is in the double-write area
+        let mut store = disk_manager.into_store().into_inner();
+        let mut raw = vec![0; store.page_size()];
+        store.read_page(page_id, &mut raw).unwrap();
+        raw[PAGE_SIZE / 2..].fill(0);
+        store.write_page(page_id, &raw).unwrap();
+
+        let store = EncryptedStore::open(store, key_provider(1)).unwrap();
+        let mut disk_manager = DiskManager::with_options(store, options).unwrap();
+        assert_eq!(disk_manager.fetch_page(page_id).unwrap().body_view(), test_page().body_view());
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let current_key_id = Arc::new(AtomicU32::new(1));
+        let provider = Box::new(TestKeyProvider { current_key_id: current_key_id.clone(), key_ids: vec![1, 2] });
+        let store = EncryptedStore::open(MemoryStore::new(), provider).unwrap();
+        let mut disk_manager = DiskManager::open(store).unwrap();
+        let old_page_id = disk_manager.allocate_page().unwrap();
+        disk_manager.write_page(old_page_id, &test_page()).unwrap();
+
+        // Rotated while the store is used from another thread
+        let (mut disk_manager, new_page_id) = thread::spawn(move || {
+            current_key_id.store(2, Ordering::Release);
+            let new_page_id = disk_manager.allocate_page().unwrap();
+            disk_manager.write_page(new_page_id, &test_page()).unwrap();
+            (disk_manager, new_page_id)
+        }).join().unwrap();
+        assert_eq!(disk_manager.fetch_page(old_page_id).unwrap().body_view(), test_page().body_view());
+        assert_eq!(disk_manager.fetch_page(new_page_id).unwrap().body_view(), test_page().body_view());
+
+        let mut store = disk_manager.into_store().into_inner();
+        let mut raw = vec![0; store.page_size()];
+        store.read_page(HEADER_PAGE_ID, &mut raw).unwrap();
+        assert_eq!(page_trailer::View::new(trailer(&raw)).key_id().read(), 2);
+        store.read_page(old_page_id, &mut raw).unwrap();
+        assert_eq!(page_trailer::View::new(trailer(&raw)).key_id().read(), 1);
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::disk_manager::{PageId, PageStore};
+
+// A store for tests which wraps another store and injects faults into its I/O.
+// Faults are either scripted with inject(), or drawn from a seeded RNG with the rates in FaultConfig,
+// so a failing run can be replayed with the same seed.
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Fault {
+    // The read fails with EIO
+    ReadError,
+    // The write fails with EIO and nothing is written
+    WriteError,
+    // The write or the allocation fails with ENOSPC
+    NoSpace,
+    // Only the first bytes of the page reach the store, and the write reports success
+    TornWrite(usize),
+}
+
+impl Fault {
+    fn is_read(self) -> bool {
+        self == Fault::ReadError
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FaultConfig {
+    // Probabilities per operation, between 0.0 and 1.0
+    pub read_error_rate: f64,
+    pub write_error_rate: f64,
+    pub no_space_rate: f64,
+    pub torn_write_rate: f64,
+    // The probability that an unsynced write survives a crash, which also reorders writes
+    pub crash_survival_rate: f64,
+    // Added to every read and write
+    pub latency: Duration,
+}
+
+// SplitMix64, good enough to draw faults and small enough to have no dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        rate > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+}
+
+pub struct FaultStore<S: PageStore> {
+    store: S,
+    config: FaultConfig,
+    rng: Rng,
+    // Scripted faults fire in order on the next operation they apply to, optionally for a page only
+    script: VecDeque<(Option<PageId>, Fault)>,
+    // The contents of each page written since the last sync as of the last sync, to roll back on a crash
+    unsynced: HashMap<PageId, Box<[u8]>>,
+    injected: usize,
+}
+
+impl<S: PageStore> FaultStore<S> {
+    pub fn new(store: S, seed: u64, config: FaultConfig) -> Self {
+        Self {
+            store,
+            config,
+            rng: Rng(seed),
+            script: VecDeque::new(),
+            unsynced: HashMap::new(),
+            injected: 0,
+        }
+    }
+
+    pub fn inject(&mut self, page_id: Option<PageId>, fault: Fault) {
+        self.script.push_back((page_id, fault));
+    }
+
+    pub fn set_config(&mut self, config: FaultConfig) {
+        self.config = config;
+    }
+
+    // The number of faults injected so far
+    pub fn injected(&self) -> usize {
+        self.injected
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    // Simulate a power loss: writes since the last sync are lost unless they survive by chance.
+    // Scripted faults which have not fired are discarded as well.
+    pub fn crash(&mut self) -> Result<()> {
+        let mut unsynced: Vec<_> = self.unsynced.drain().collect();
+        // Sort for the RNG to be deterministic
+        unsynced.sort_by_key(|(page_id, _)| page_id.to_u32());
+        for (page_id, synced) in unsynced {
+            if !self.rng.chance(self.config.crash_survival_rate) {
+                self.store.write_page(page_id, &synced)?;
+            }
+        }
+        self.script.clear();
+        self.store.sync()
+    }
+
+    fn next_fault(&mut self, page_id: PageId, is_read: bool) -> Option<Fault> {
+        thread::sleep(self.config.latency);
+        let scripted = self.script.iter()
+            .position(|&(target, fault)| fault.is_read() == is_read && target.is_none_or(|target| target == page_id));
+        let fault = match scripted {
+            Some(index) => self.script.remove(index).map(|(_, fault)| fault),
+            None if is_read => self.rng.chance(self.config.read_error_rate).then_some(Fault::ReadError),
+            None => {
+                if self.rng.chance(self.config.write_error_rate) {
+                    Some(Fault::WriteError)
+                } else if self.rng.chance(self.config.no_space_rate) {
+                    Some(Fault::NoSpace)
+                } else if self.rng.chance(self.config.torn_write_rate) {
+                    // Tear at a sector boundary like a real disk
+                    let sectors = self.store.page_size() / 512;
+                    Some(Fault::TornWrite((1 + self.rng.next_u64() as usize % (sectors - 1)) * 512))
+                } else {
+                    None
+                }
+            }
+        };
+        if fault.is_some() {
+            self.injected += 1;
+        }
+        fault
+    }
+
+    fn remember_synced(&mut self, page_id: PageId) -> Result<()> {
+        if !self.unsynced.contains_key(&page_id) {
+            let mut synced = vec![0; self.store.page_size()].into_boxed_slice();
+            self.store.read_page(page_id, &mut synced)?;
+            self.unsynced.insert(page_id, synced);
+        }
+        Ok(())
+    }
+
+    // Only a scripted NoSpace without a target page fails an allocation
+    fn check_space(&mut self) -> Result<()> {
+        if let Some((index, _)) = self.script.iter().enumerate().find(|(_, (target, fault))| target.is_none() && *fault == Fault::NoSpace) {
+            self.script.remove(index);
+            self.injected += 1;
+            return Err(injected_error(libc::ENOSPC)).context("failed to extend the file");
+        }
+        Ok(())
+    }
+}
+
+fn injected_error(code: i32) -> io::Error {
+    io::Error::from_raw_os_error(code)
+}
+
+impl<S: PageStore> PageStore for FaultStore<S> {
+    fn read_page(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()> {
+        if let Some(Fault::ReadError) = self.next_fault(page_id, true) {
+            return Err(injected_error(libc::EIO)).context("failed to read bytes from the file");
+        }
+        self.store.read_page(page_id, buf)
+    }
+
+    fn write_page(&mut self, page_id: PageId, buf: &[u8]) -> Result<()> {
+        match self.next_fault(page_id, false) {
+            Some(Fault::WriteError) => Err(injected_error(libc::EIO)).context("failed to write bytes into the file"),
+            Some(Fault::NoSpace) => Err(injected_error(libc::ENOSPC)).context("failed to write bytes into the file"),
+            Some(Fault::TornWrite(length)) => {
+                self.remember_synced(page_id)?;
+                let mut torn = vec![0; buf.len()];
+                self.store.read_page(page_id, &mut torn)?;
+                let length = length.min(buf.len());
+                torn[..length].copy_from_slice(&buf[..length]);
+                self.store.write_page(page_id, &torn)
+            }
+            _ => {
+                self.remember_synced(page_id)?;
+                self.store.write_page(page_id, buf)
+            }
+        }
+    }
+
+    fn allocate(&mut self) -> Result<PageId> {
+        self.check_space()?;
+        self.store.allocate()
+    }
+
+    fn allocate_pages(&mut self, count: usize) -> Result<PageId> {
+        self.check_space()?;
+        self.store.allocate_pages(count)
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        self.unsynced.retain(|page_id, _| page_id.to_u64() < len);
+        self.store.truncate(len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.store.sync()?;
+        self.unsynced.clear();
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.store.len()
+    }
+
+    fn page_size(&self) -> usize {
+        self.store.page_size()
+    }
+
+    fn pages_per_segment(&self) -> u32 {
+        self.store.pages_per_segment()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, PAGE_SIZE, SlottedPage};
+    use crate::disk_manager::memory_store::MemoryStore;
+    use crate::disk_manager::{DiskError, DiskManager, DiskOptions};
+
+    use super::*;
+
+    fn test_page(value: u8) -> SlottedPage {
+        let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+        page.add_cell(0, b"key", &[value; 1024]).unwrap();
+        page
+    }
+
+    fn os_error(err: &anyhow::Error) -> Option<i32> {
+        err.downcast_ref::<io::Error>().and_then(|e| e.raw_os_error())
+    }
+
+    #[test]
+    fn test_scripted_faults() {
+        let store = FaultStore::new(MemoryStore::new(), 0, FaultConfig::default());
+        let mut manager = DiskManager::open(store).unwrap();
+        let page_id = manager.allocate_page().unwrap();
+        manager.write_page(page_id, &test_page(1)).unwrap();
+
+        manager.store.inject(Some(page_id), Fault::WriteError);
+        let err = manager.write_page(page_id, &test_page(2)).err().unwrap();
+        assert_eq!(os_error(&err), Some(libc::EIO));
+        assert_eq!(manager.fetch_page(page_id).unwrap().body_view(), test_page(1).body_view());
+
+        manager.store.inject(None, Fault::ReadError);
+        let err = manager.fetch_page(page_id).err().unwrap();
+        assert_eq!(os_error(&err), Some(libc::EIO));
+        assert_eq!(manager.fetch_page(page_id).is_ok(), true);
+
+        manager.store.inject(None, Fault::NoSpace);
+        let err = manager.allocate_page().err().unwrap();
+        assert_eq!(os_error(&err), Some(libc::ENOSPC));
+
+        // A failed page in a batch is reported on its own
+        let other_page_id = manager.allocate_page().unwrap();
+        manager.store.inject(Some(other_page_id), Fault::NoSpace);
+        let (page, other_page) = (test_page(3), test_page(4));
+        let err = manager.write_pages(&[(page_id, &page), (other_page_id, &other_page)]).err().unwrap();
+        match err.downcast_ref::<DiskError>() {
+            Some(DiskError::PartialWrite(failures)) => {
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].0, other_page_id);
+                assert_eq!(os_error(&failures[0].1), Some(libc::ENOSPC));
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+        assert_eq!(manager.store.injected(), 4);
+    }
+
+    #[test]
+    fn test_no_space_for_extent() {
+        let store = FaultStore::new(MemoryStore::new(), 0, FaultConfig::default());
+        let options = DiskOptions { extent_size: 8, ..Default::default() };
+        let mut manager = DiskManager::with_options(store, options).unwrap();
+
+        // The store grows by a single page when there is no room for an extent
+        manager.store.inject(None, Fault::NoSpace);
+        assert_eq!(manager.allocate_page().unwrap(), PageId(1));
+        assert_eq!(manager.store.len(), 2);
+        assert_eq!(manager.allocated_end(), &PageId(2));
+
+        manager.store.inject(None, Fault::NoSpace);
+        manager.store.inject(None, Fault::NoSpace);
+        let err = manager.allocate_page().err().unwrap();
+        assert_eq!(os_error(&err), Some(libc::ENOSPC));
+        assert_eq!(manager.allocated_end(), &PageId(2));
+        assert_eq!(manager.allocate_page().unwrap(), PageId(2));
+        assert_eq!(manager.store.len(), 10);
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let store = FaultStore::new(MemoryStore::new(), 0, FaultConfig::default());
+        let mut manager = DiskManager::open(store).unwrap();
+        let page_id = manager.allocate_page().unwrap();
+        manager.write_page(page_id, &test_page(1)).unwrap();
+
+        manager.store.inject(Some(page_id), Fault::TornWrite(PAGE_SIZE / 2));
+        assert_eq!(manager.write_page(page_id, &test_page(2)).is_ok(), true);
+        let err = manager.fetch_page(page_id).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::ChecksumMismatch(id)) if *id == page_id), true);
+
+        // The double-write area restores the page on the next open
+        let options = DiskOptions { double_write_slots: 4, ..Default::default() };
+        let store = FaultStore::new(MemoryStore::new(), 0, FaultConfig::default());
+        let mut manager = DiskManager::with_options(store, options.clone()).unwrap();
+        let page_id = manager.allocate_page().unwrap();
+        manager.store.inject(Some(page_id), Fault::TornWrite(512));
+        manager.write_page(page_id, &test_page(2)).unwrap();
+        let err = manager.fetch_page(page_id).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::ChecksumMismatch(id)) if *id == page_id), true);
+
+        let mut manager = DiskManager::with_options(manager.into_store(), options).unwrap();
+        let mut page = manager.fetch_page(page_id).unwrap();
+        assert_eq!(page.valid(), true);
+        assert_eq!(page.body_view(), test_page(2).body_view());
+    }
+
+    #[test]
+    fn test_crash_drops_unsynced_writes() {
+        let store = FaultStore::new(MemoryStore::new(), 0, FaultConfig::default());
+        let mut manager = DiskManager::open(store).unwrap();
+        let page_id = manager.allocate_page().unwrap();
+        manager.write_page(page_id, &test_page(1)).unwrap();
+        manager.sync().unwrap();
+        manager.write_page(page_id, &test_page(2)).unwrap();
+        let unsynced_page_id = manager.allocate_page().unwrap();
+        manager.write_page(unsynced_page_id, &test_page(3)).unwrap();
+
+        let mut store = manager.into_store();
+        store.crash().unwrap();
+        let mut manager = DiskManager::open(store).unwrap();
+        assert_eq!(manager.fetch_page(page_id).unwrap().body_view(), test_page(1).body_view());
+        // The allocation was never synced either
+        assert_eq!(manager.next_page_id(), &unsynced_page_id);
+    }
+
+    #[test]
+    fn test_seeded_faults() {
+        let config = FaultConfig {
+            read_error_rate: 0.1,
+            write_error_rate: 0.1,
+            no_space_rate: 0.05,
+            torn_write_rate: 0.1,
+            ..Default::default()
+        };
+        let run = |seed: u64| {
+            let mut store = FaultStore::new(MemoryStore::new(), seed, config.clone());
+            let page_id = store.allocate().unwrap();
+            let mut buf = [0; PAGE_SIZE];
+            let results: Vec<bool> = (0..200)
+                .map(|i| match i % 2 {
+                    0 => store.write_page(page_id, &[i as u8; PAGE_SIZE]).is_ok(),
+                    _ => store.read_page(page_id, &mut buf).is_ok(),
+                })
+                .collect();
+            (results, store.injected())
+        };
+        let (results, injected) = run(42);
+        assert_eq!(injected > 0, true);
+        assert_eq!(results.iter().any(|ok| !ok), true);
+        assert_eq!(run(42), (results, injected));
+    }
+
+    #[test]
+    fn test_latency() {
+        let config = FaultConfig { latency: Duration::from_millis(5), ..Default::default() };
+        let mut store = FaultStore::new(MemoryStore::new(), 0, config);
+        let page_id = store.allocate().unwrap();
+        let start = Instant::now();
+        for _ in 0..4 {
+            store.write_page(page_id, &[0; PAGE_SIZE]).unwrap();
+        }
+        assert_eq!(start.elapsed() >= Duration::from_millis(20), true);
+    }
+}
@@ -0,0 +1,691 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::btree::slotted_page::{is_valid_page_size, PAGE_ALIGNMENT, PAGE_SIZE, SlottedPage};
+use crate::disk_manager::{check_buffer_size, DiskError, PageId, PageStore};
+use crate::disk_manager::header::peek_page_size;
+
+#[derive(Debug, Default, Clone)]
+pub struct FileOptions {
+    // Bypass the OS page cache with O_DIRECT on Linux, since pages are cached by BufferManager anyway.
+    // It falls back to buffered I/O when the file system rejects the flag.
+    pub direct_io: bool,
+    // Split the pages into segment files of this many bytes, like PostgreSQL relation segments.
+    // The first segment is the file itself and the following ones are suffixed with ".1", ".2" and so on.
+    // It is recorded in the file header, and DiskManager rejects a database opened with another one.
+    pub segment_size: Option<u64>,
+    // Read-only opens take a shared lock on the file, and read-write opens take an exclusive one
+    pub read_only: bool,
+    // The page size of a new file, PAGE_SIZE by default.
+    // An existing file keeps the page size recorded in its header, and a different one is rejected.
+    pub page_size: Option<usize>,
+}
+
+// The number of pages written with a single pwritev(2), which is IOV_MAX on Linux
+const MAX_PAGES_PER_WRITE: usize = 1024;
+
+pub struct FileStore {
+    file_path: PathBuf,
+    segments: Vec<File>,
+    pages_per_segment: u64,
+    number_of_pages: u64,
+    direct_io: bool,
+    read_only: bool,
+    page_size: usize,
+    // Bounce buffer for callers passing a buffer which is not aligned, since O_DIRECT requires
+    // the buffer to be aligned as well as the offset and the length
+    aligned_page: SlottedPage,
+}
+
+impl FileStore {
+    pub fn open(file_path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_options(file_path, FileOptions::default())
+    }
+
+    pub fn with_options(file_path: impl AsRef<Path>, options: FileOptions) -> Result<Self> {
+        let file_path = file_path.as_ref().to_path_buf();
+        let err = format!("failed to open file, file_path: {:?}", file_path);
+        let (file, direct_io) = open_file(&file_path, options.direct_io, options.read_only).context(err)?;
+        // The first segment stands for the whole database
+        lock_file(&file, &file_path, options.read_only)?;
+        let page_size = detect_page_size(&file, options.page_size)?;
+        let pages_per_segment = match options.segment_size {
+            Some(size) if size == 0 || size % page_size as u64 != 0 || size / page_size as u64 > u32::MAX as u64 => {
+                return Err(anyhow!("segment size must be a positive multiple of the page size: {}", size));
+            }
+            Some(size) => size / page_size as u64,
+            None => u64::MAX,
+        };
+        let mut store = Self {
+            file_path,
+            segments: vec![file],
+            pages_per_segment,
+            number_of_pages: 0,
+            direct_io,
+            read_only: options.read_only,
+            page_size,
+            aligned_page: SlottedPage::zeroed(page_size)?,
+        };
+
+        // Pick up the following segments as long as the previous ones are full
+        loop {
+            let last = store.segments.last().unwrap();
+            // A partially written trailing page is not part of the store
+            let pages = last.metadata().context("failed to read the file metadata")?.len() / page_size as u64;
+            store.number_of_pages += pages.min(pages_per_segment);
+            let next_path = store.segment_path(store.segments.len());
+            if options.segment_size.is_none() || pages < pages_per_segment || !next_path.exists() {
+                break;
+            }
+            let (file, _) = open_file(&next_path, store.direct_io, store.read_only)
+                .with_context(|| format!("failed to open the segment file: {:?}", next_path))?;
+            store.segments.push(file);
+        }
+
+        Ok(store)
+    }
+
+    pub fn is_direct_io(&self) -> bool {
+        self.direct_io
+    }
+
+    pub fn number_of_segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    // The segment file holding the page and the offset of the page in it
+    pub fn locate(&self, page_id: PageId) -> (&File, u64) {
+        let (segment, offset) = self.position(page_id);
+        (&self.segments[segment], offset)
+    }
+
+    fn position(&self, page_id: PageId) -> (usize, u64) {
+        let segment = page_id.to_u64() / self.pages_per_segment;
+        let offset = (page_id.to_u64() % self.pages_per_segment) * self.page_size as u64;
+        (segment as usize, offset)
+    }
+
+    fn segment_path(&self, segment: usize) -> PathBuf {
+        if segment == 0 {
+            return self.file_path.clone();
+        }
+        let mut path = self.file_path.clone().into_os_string();
+        path.push(format!(".{}", segment));
+        PathBuf::from(path)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly.into());
+        }
+        Ok(())
+    }
+
+    fn check_allocated(&self, page_id: PageId) -> Result<()> {
+        if page_id.to_u64() >= self.number_of_pages {
+            return Err(DiskError::PageNotAllocated(page_id).into());
+        }
+        Ok(())
+    }
+
+    // Writes contiguous pages with one system call.
+    // The run has to be sorted by the page id and must not cross a segment boundary.
+    fn write_run(&self, run: &[(PageId, &[u8])]) -> io::Result<()> {
+        let iovecs: Vec<libc::iovec> = run.iter()
+            .map(|(_, buf)| libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: self.page_size })
+            .collect();
+        let (file, offset) = self.locate(run[0].0);
+        // SAFETY: the iovecs point to buffers borrowed for the duration of the call
+        let written = unsafe { libc::pwritev(file.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as libc::c_int, offset as libc::off_t) };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if written as usize != self.page_size * run.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "short write"));
+        }
+        Ok(())
+    }
+
+    fn needs_bounce(&self, buf: &[u8]) -> bool {
+        self.direct_io && !(buf.as_ptr() as usize).is_multiple_of(PAGE_ALIGNMENT)
+    }
+}
+
+// The page size of the file recorded in its header, or the requested one for a new file
+pub fn detect_page_size(file: &File, requested: Option<usize>) -> Result<usize> {
+    if let Some(page_size) = requested {
+        if !is_valid_page_size(page_size) {
+            return Err(DiskError::UnsupportedPageSize(page_size).into());
+        }
+    }
+    let mut head = SlottedPage::zeroed(PAGE_SIZE)?;
+    let len = file.metadata().context("failed to read the file metadata")?.len();
+    if len >= PAGE_SIZE as u64 {
+        file.read_exact_at(head.to_bytes_mut(), 0).context("failed to read the file header")?;
+    }
+    match (peek_page_size(head.to_bytes()), requested) {
+        (Some(page_size), Some(requested)) if page_size != requested => Err(DiskError::PageSizeMismatch(page_size as u32).into()),
+        (Some(page_size), _) => Ok(page_size),
+        (None, requested) => Ok(requested.unwrap_or(PAGE_SIZE)),
+    }
+}
+
+fn open_file(file_path: &Path, direct_io: bool, read_only: bool) -> io::Result<(File, bool)> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(!read_only).create(!read_only).truncate(false);
+    if direct_io {
+        if let Some(file) = open_direct(&options, file_path)? {
+            return Ok((file, true));
+        }
+    }
+
+    Ok((options.open(file_path)?, false))
+}
+
+// Takes an advisory lock with flock(2), so that another process opening the same database fails
+// instead of corrupting pages. The lock is released when the file is closed.
+pub fn lock_file(file: &File, file_path: &Path, shared: bool) -> Result<()> {
+    let operation = if shared { libc::LOCK_SH } else { libc::LOCK_EX };
+    // SAFETY: flock(2) only takes the file descriptor owned by the file
+    let ret = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Err(DiskError::Locked(file_path.to_path_buf()).into());
+        }
+        return Err(anyhow!(err).context(format!("failed to lock the file: {:?}", file_path)));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(options: &OpenOptions, file_path: &Path) -> io::Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = options.clone();
+    match options.custom_flags(libc::O_DIRECT).open(file_path) {
+        Ok(file) => Ok(Some(file)),
+        // e.g. tmpfs on older kernels
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_options: &OpenOptions, _file_path: &Path) -> io::Result<Option<File>> {
+    Ok(None)
+}
+
+// Reserves the blocks with fallocate(2), so that the file system can lay out the extent contiguously.
+// It falls back to set_len(), which leaves a sparse range, when the file system does not support it.
+#[cfg(target_os = "linux")]
+fn extend_file(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    // SAFETY: fallocate(2) only takes the file descriptor owned by the file
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, offset as libc::off_t, len as libc::off_t) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => file.set_len(offset + len),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn extend_file(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    file.set_len(offset + len)
+}
+
+impl PageStore for FileStore {
+    fn read_page(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()> {
+        check_buffer_size(buf, self.page_size)?;
+        self.check_allocated(page_id)?;
+        let (segment, offset) = self.position(page_id);
+        if self.needs_bounce(buf) {
+            self.segments[segment].read_exact_at(self.aligned_page.to_bytes_mut(), offset).context("failed to read bytes from the file")?;
+            buf.copy_from_slice(self.aligned_page.to_bytes());
+        } else {
+            self.segments[segment].read_exact_at(buf, offset).context("failed to read bytes from the file")?;
+        }
+
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_id: PageId, buf: &[u8]) -> Result<()> {
+        check_buffer_size(buf, self.page_size)?;
+        self.check_writable()?;
+        self.check_allocated(page_id)?;
+        let (segment, offset) = self.position(page_id);
+        if self.needs_bounce(buf) {
+            self.aligned_page.to_bytes_mut().copy_from_slice(buf);
+            self.segments[segment].write_all_at(self.aligned_page.to_bytes(), offset).context("failed to write bytes into the file")?;
+        } else {
+            self.segments[segment].write_all_at(buf, offset).context("failed to write bytes into the file")?;
+        }
+
+        Ok(())
+    }
+
+    // Sorts the pages and coalesces contiguous runs into pwritev(2) calls.
+    // When a run fails, its pages are written one by one to find out which pages failed.
+    fn write_pages(&mut self, requests: &[(PageId, &[u8])]) -> Result<()> {
+        self.check_writable()?;
+        // write_run() passes page_size bytes of each buffer to the kernel
+        for (_, buf) in requests {
+            check_buffer_size(buf, self.page_size)?;
+        }
+        let mut sorted = requests.to_vec();
+        // Stable, so that the last one wins when a page shows up twice
+        sorted.sort_by_key(|(page_id, _)| page_id.to_u32());
+
+        let mut failures = vec![];
+        let mut start = 0;
+        while start < sorted.len() {
+            let mut end = start + 1;
+            while end < sorted.len()
+                && end - start < MAX_PAGES_PER_WRITE
+                && sorted[end].0.to_u32() == sorted[end - 1].0.to_u32() + 1
+                && !sorted[end].0.to_u64().is_multiple_of(self.pages_per_segment) {
+                end += 1;
+            }
+            let run = &sorted[start..end];
+            let coalesce = run.iter().all(|(page_id, buf)| page_id.to_u64() < self.number_of_pages && !self.needs_bounce(buf));
+            if !coalesce || self.write_run(run).is_err() {
+                for (page_id, buf) in run {
+                    if let Err(err) = self.write_page(*page_id, buf) {
+                        failures.push((*page_id, err));
+                    }
+                }
+            }
+            start = end;
+        }
+        if !failures.is_empty() {
+            return Err(DiskError::PartialWrite(failures).into());
+        }
+
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<PageId> {
+        self.allocate_pages(1)
+    }
+
+    // Each segment in the extent is grown with a single fallocate(2)
+    fn allocate_pages(&mut self, count: usize) -> Result<PageId> {
+        self.check_writable()?;
+        let first = PageId(self.number_of_pages as u32);
+        let mut remaining = count as u64;
+        while remaining > 0 {
+            let page_id = PageId(self.number_of_pages as u32);
+            let segment = (page_id.to_u64() / self.pages_per_segment) as usize;
+            if segment == self.segments.len() {
+                let path = self.segment_path(segment);
+                let (file, _) = open_file(&path, self.direct_io, false)
+                    .with_context(|| format!("failed to create the segment file: {:?}", path))?;
+                self.segments.push(file);
+            }
+            let pages = remaining.min(self.pages_per_segment - page_id.to_u64() % self.pages_per_segment);
+            let (file, offset) = self.locate(page_id);
+            extend_file(file, offset, pages * self.page_size as u64).context("failed to extend the file")?;
+            self.number_of_pages += pages;
+            remaining -= pages;
+        }
+
+        Ok(first)
+    }
+
+    // Segment files past the new end are removed, and the first one is always kept
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        self.check_writable()?;
+        if len >= self.number_of_pages {
+            return Ok(());
+        }
+        let number_of_segments = len.div_ceil(self.pages_per_segment).max(1) as usize;
+        while self.segments.len() > number_of_segments {
+            self.segments.pop();
+            let path = self.segment_path(self.segments.len());
+            fs::remove_file(&path).with_context(|| format!("failed to remove the segment file: {:?}", path))?;
+        }
+        let last = number_of_segments - 1;
+        let pages = len - last as u64 * self.pages_per_segment;
+        self.segments[last].set_len(pages * self.page_size as u64).context("failed to truncate the file")?;
+        self.number_of_pages = len;
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        for file in self.segments.iter() {
+            file.sync_data().context("failed to sync the file")?;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.number_of_pages
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn pages_per_segment(&self) -> u32 {
+        match self.pages_per_segment {
+            u64::MAX => 0,
+            pages => pages as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, SlottedPage};
+    use crate::disk_manager::DiskManager;
+
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 0);
+        let mut buf = [0; PAGE_SIZE];
+        let err = store.read_page(PageId(0), &mut buf).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(PageId(0)))), true);
+
+        for i in 0..3 {
+            assert_eq!(store.allocate().unwrap(), PageId(i));
+        }
+        assert_eq!(store.write_page(PageId(1), &[0xab; PAGE_SIZE]).is_ok(), true);
+        assert_eq!(store.sync().is_ok(), true);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 3 * PAGE_SIZE as u64);
+        drop(store);
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.read_page(PageId(1), &mut buf).is_ok(), true);
+        assert_eq!(buf, [0xab; PAGE_SIZE]);
+        assert_eq!(store.read_page(PageId(2), &mut buf).is_ok(), true);
+        assert_eq!(buf, [0; PAGE_SIZE]);
+    }
+
+    #[test]
+    fn test_write_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        let number_of_pages = MAX_PAGES_PER_WRITE * 2 + 100;
+
+        let mut store = FileStore::open(&path).unwrap();
+        for _ in 0..number_of_pages {
+            store.allocate().unwrap();
+        }
+        let pages: Vec<[u8; PAGE_SIZE]> = (0..number_of_pages).map(|i| [(i % 251) as u8; PAGE_SIZE]).collect();
+        // Shuffled, with a gap and a page which is not allocated
+        let mut requests: Vec<(PageId, &[u8])> = pages.iter().enumerate()
+            .filter(|(i, _)| *i != 10)
+            .map(|(i, page)| (PageId(((i * 7) % number_of_pages) as u32), &page[..]))
+            .collect();
+        let unallocated = PageId(number_of_pages as u32);
+        requests.push((unallocated, &pages[0][..]));
+
+        let err = store.write_pages(&requests).err().unwrap();
+        match err.downcast_ref::<DiskError>() {
+            Some(DiskError::PartialWrite(failures)) => {
+                assert_eq!(failures.len(), 1);
+                assert_eq!(failures[0].0, unallocated);
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+
+        let mut buf = [0; PAGE_SIZE];
+        for (page_id, page) in requests.iter().filter(|(page_id, _)| *page_id != unallocated) {
+            store.read_page(*page_id, &mut buf).unwrap();
+            assert_eq!(&buf[..], *page);
+        }
+        store.read_page(PageId(70), &mut buf).unwrap();
+        assert_eq!(buf, [0; PAGE_SIZE]);
+
+        // The whole batch is rejected when a buffer is not a page long
+        let short = [0xff; PAGE_SIZE / 2];
+        let err = store.write_pages(&[(PageId(70), &pages[1][..]), (PageId(71), &short[..])]).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageSizeMismatch(_))), true);
+        store.read_page(PageId(70), &mut buf).unwrap();
+        assert_eq!(buf, [0; PAGE_SIZE]);
+    }
+
+    #[test]
+    fn test_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        let options = FileOptions { segment_size: Some(4 * PAGE_SIZE as u64), ..Default::default() };
+        let number_of_pages = 10;
+
+        let mut store = FileStore::with_options(&path, options.clone()).unwrap();
+        for i in 0..number_of_pages {
+            assert_eq!(store.allocate().unwrap(), PageId(i));
+            assert_eq!(store.write_page(PageId(i), &[i as u8; PAGE_SIZE]).is_ok(), true);
+        }
+        assert_eq!(store.number_of_segments(), 3);
+        // A batch crossing segment boundaries
+        let pages: Vec<[u8; PAGE_SIZE]> = (0..number_of_pages).map(|i| [i as u8 + 100; PAGE_SIZE]).collect();
+        let requests: Vec<(PageId, &[u8])> = pages.iter().enumerate().skip(2).map(|(i, page)| (PageId(i as u32), &page[..])).collect();
+        assert_eq!(store.write_pages(&requests).is_ok(), true);
+        assert_eq!(store.sync().is_ok(), true);
+        drop(store);
+
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4 * PAGE_SIZE as u64);
+        assert_eq!(fs::metadata(dir.path().join("test.idb.1")).unwrap().len(), 4 * PAGE_SIZE as u64);
+        assert_eq!(fs::metadata(dir.path().join("test.idb.2")).unwrap().len(), 2 * PAGE_SIZE as u64);
+
+        let mut store = FileStore::with_options(&path, options).unwrap();
+        assert_eq!(store.len(), number_of_pages as u64);
+        assert_eq!(store.number_of_segments(), 3);
+        let mut buf = [0; PAGE_SIZE];
+        for i in 0..number_of_pages {
+            store.read_page(PageId(i), &mut buf).unwrap();
+            let expected = if i < 2 { i as u8 } else { i as u8 + 100 };
+            assert_eq!(buf, [expected; PAGE_SIZE]);
+        }
+
+        let options = FileOptions { segment_size: Some(PAGE_SIZE as u64 + 1), ..Default::default() };
+        assert_eq!(FileStore::with_options(&path, options).is_err(), true);
+    }
+
+    #[test]
+    fn test_segment_size_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        let options = FileOptions { segment_size: Some(4 * PAGE_SIZE as u64), ..Default::default() };
+
+        let mut manager = DiskManager::open(FileStore::with_options(&path, options.clone()).unwrap()).unwrap();
+        for _ in 0..6 {
+            manager.allocate_page().unwrap();
+        }
+        drop(manager);
+
+        // Without segments, or with segments of another size
+        let err = DiskManager::open(FileStore::open(&path).unwrap()).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::SegmentSizeMismatch(4))), true);
+        let other = FileOptions { segment_size: Some(8 * PAGE_SIZE as u64), ..Default::default() };
+        let err = DiskManager::open(FileStore::with_options(&path, other).unwrap()).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::SegmentSizeMismatch(4))), true);
+
+        let manager = DiskManager::open(FileStore::with_options(&path, options).unwrap()).unwrap();
+        assert_eq!(manager.next_page_id(), &PageId(7));
+    }
+
+    #[test]
+    fn test_page_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        let page_size = PAGE_SIZE * 2;
+
+        let options = FileOptions { page_size: Some(page_size), ..Default::default() };
+        let store = FileStore::with_options(&path, options).unwrap();
+        assert_eq!(store.page_size(), page_size);
+        let mut manager = DiskManager::open(store).unwrap();
+        let page_id = manager.allocate_page().unwrap();
+        assert_eq!(manager.write_page(page_id, &SlottedPage::new(page_size, MAGIC_NUMBER_LEAF).unwrap()).is_ok(), true);
+        drop(manager);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2 * page_size as u64);
+
+        // The page size is read from the file header
+        let mut manager = DiskManager::open(FileStore::open(&path).unwrap()).unwrap();
+        assert_eq!(manager.page_size(), page_size);
+        assert_eq!(manager.fetch_page(page_id).unwrap().valid(), true);
+        drop(manager);
+
+        let options = FileOptions { page_size: Some(PAGE_SIZE), ..Default::default() };
+        let err = FileStore::with_options(&path, options).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageSizeMismatch(8192))), true);
+    }
+
+    #[test]
+    fn test_allocate_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        let options = FileOptions { segment_size: Some(4 * PAGE_SIZE as u64), ..Default::default() };
+
+        let mut store = FileStore::with_options(&path, options.clone()).unwrap();
+        assert_eq!(store.allocate().unwrap(), PageId(0));
+        // The extent spans three segments
+        assert_eq!(store.allocate_pages(9).unwrap(), PageId(1));
+        assert_eq!(store.len(), 10);
+        assert_eq!(store.number_of_segments(), 3);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4 * PAGE_SIZE as u64);
+        assert_eq!(fs::metadata(store.segment_path(2)).unwrap().len(), 2 * PAGE_SIZE as u64);
+
+        // Preallocated pages are zeroed and writable
+        let mut buf = [0xff; PAGE_SIZE];
+        assert_eq!(store.read_page(PageId(9), &mut buf).is_ok(), true);
+        assert_eq!(buf, [0; PAGE_SIZE]);
+        assert_eq!(store.write_page(PageId(9), &[0xab; PAGE_SIZE]).is_ok(), true);
+        drop(store);
+
+        let store = FileStore::with_options(&path, options).unwrap();
+        assert_eq!(store.len(), 10);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        let options = FileOptions { segment_size: Some(4 * PAGE_SIZE as u64), ..Default::default() };
+
+        let mut store = FileStore::with_options(&path, options.clone()).unwrap();
+        store.allocate_pages(10).unwrap();
+        assert_eq!(store.number_of_segments(), 3);
+        assert_eq!(store.truncate(6).is_ok(), true);
+        assert_eq!(store.len(), 6);
+        assert_eq!(store.number_of_segments(), 2);
+        assert_eq!(store.segment_path(2).exists(), false);
+        assert_eq!(fs::metadata(store.segment_path(1)).unwrap().len(), 2 * PAGE_SIZE as u64);
+        let mut buf = [0; PAGE_SIZE];
+        let err = store.read_page(PageId(6), &mut buf).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(PageId(6)))), true);
+
+        // Exactly at a segment boundary
+        assert_eq!(store.truncate(4).is_ok(), true);
+        assert_eq!(store.number_of_segments(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4 * PAGE_SIZE as u64);
+        drop(store);
+
+        let mut store = FileStore::with_options(&path, options).unwrap();
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.allocate().unwrap(), PageId(4));
+        assert_eq!(store.number_of_segments(), 2);
+    }
+
+    #[test]
+    fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        let read_only = FileOptions { read_only: true, ..Default::default() };
+
+        let mut store = FileStore::open(&path).unwrap();
+        store.allocate().unwrap();
+        // flock(2) locks conflict between open file descriptions even in the same process
+        let err = FileStore::open(&path).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::Locked(p)) if *p == path), true);
+        let err = FileStore::with_options(&path, read_only.clone()).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::Locked(_))), true);
+        drop(store);
+
+        // Readers share the lock, but a writer is still locked out
+        let mut reader1 = FileStore::with_options(&path, read_only.clone()).unwrap();
+        let reader2 = FileStore::with_options(&path, read_only).unwrap();
+        assert_eq!(reader2.len(), 1);
+        let err = FileStore::open(&path).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::Locked(_))), true);
+
+        let mut buf = [0; PAGE_SIZE];
+        assert_eq!(reader1.read_page(PageId(0), &mut buf).is_ok(), true);
+        let err = reader1.write_page(PageId(0), &buf).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::ReadOnly)), true);
+        assert_eq!(reader1.allocate().is_err(), true);
+        drop(reader1);
+        drop(reader2);
+
+        assert_eq!(FileStore::open(&path).is_ok(), true);
+    }
+
+    // Probed apart from open_direct(), which fails with EINVAL when the file system has no O_DIRECT
+    #[cfg(target_os = "linux")]
+    fn supports_direct_io(dir: &Path) -> bool {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let ret = OpenOptions::new().write(true).create(true).custom_flags(libc::O_DIRECT).open(dir.join("probe"));
+        !matches!(ret, Err(e) if e.raw_os_error() == Some(libc::EINVAL))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn supports_direct_io(_dir: &Path) -> bool {
+        false
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+
+        let options = FileOptions { direct_io: true, ..Default::default() };
+        let mut store = FileStore::with_options(&path, options.clone()).unwrap();
+        // The store falls back to buffered I/O on a file system without O_DIRECT such as tmpfs,
+        // and the pages are read and written the same either way
+        assert_eq!(store.is_direct_io(), supports_direct_io(dir.path()));
+        assert_eq!(store.allocate().unwrap(), PageId(0));
+        assert_eq!(store.allocate().unwrap(), PageId(1));
+
+        // An aligned page
+        let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+        page.add_cell(0, b"key", b"value").unwrap();
+        assert_eq!(store.write_page(PageId(0), page.to_bytes()).is_ok(), true);
+
+        // A buffer which is very likely not aligned
+        let mut buf = vec![0xef_u8; PAGE_SIZE + 1];
+        let unaligned: &mut [u8; PAGE_SIZE] = (&mut buf[1..]).try_into().unwrap();
+        assert_eq!(store.write_page(PageId(1), unaligned).is_ok(), true);
+        drop(store);
+
+        let mut store = FileStore::with_options(&path, options).unwrap();
+        let mut fetched = SlottedPage::zeroed(PAGE_SIZE).unwrap();
+        assert_eq!(store.read_page(PageId(0), fetched.to_bytes_mut()).is_ok(), true);
+        assert_eq!(fetched.valid(), true);
+        assert_eq!(fetched.cell_view(0).body(), b"keyvalue");
+
+        let mut buf = vec![0_u8; PAGE_SIZE + 1];
+        let unaligned: &mut [u8; PAGE_SIZE] = (&mut buf[1..]).try_into().unwrap();
+        assert_eq!(store.read_page(PageId(1), unaligned).is_ok(), true);
+        assert_eq!(unaligned, &[0xef_u8; PAGE_SIZE]);
+    }
+}
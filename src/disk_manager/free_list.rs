@@ -0,0 +1,142 @@
+use binary_layout::define_layout;
+
+use crate::btree::slotted_page::{body_size, MAGIC_NUMBER_FREE_LIST, SlottedPage};
+use crate::disk_manager::{DiskError, PageId};
+
+/*
+ A free list trunk page keeps the ids of free pages in its body.
+ Trunks are chained from the file header, and a trunk page is reused itself once it becomes empty.
+ -------------------------------------------------------------------
+ |                    Next trunk page id (4b)                      |
+ -------------------------------------------------------------------
+ |                     Number of leaves (4b)                       |
+ -------------------------------------------------------------------
+ |                       Leaf page ids (4b each)                   |
+ -------------------------------------------------------------------
+ */
+
+const TRUNK_HEADER_SIZE: usize = 8;
+
+// The number of page ids a trunk holds
+pub fn trunk_capacity(page_size: usize) -> usize {
+    (body_size(page_size) - TRUNK_HEADER_SIZE) / 4
+}
+
+define_layout!(trunk, BigEndian, {
+    next_trunk_page_id: u32,
+    number_of_leaves: u32,
+    leaves: [u8],
+});
+
+pub struct FreeListTrunk {
+    page: SlottedPage,
+}
+
+impl FreeListTrunk {
+    pub fn new(page_size: usize, next_trunk_page_id: PageId) -> Result<Self, DiskError> {
+        let mut page = SlottedPage::new(page_size, MAGIC_NUMBER_FREE_LIST)?;
+        trunk::View::new(page.body_view_mut()).next_trunk_page_id_mut().write(next_trunk_page_id.to_u32());
+        Ok(Self { page })
+    }
+
+    pub fn from_page(page_id: PageId, mut page: SlottedPage) -> Result<Self, DiskError> {
+        let magic_number = page.header_view().magic_number().read();
+        let check_sum = page.check_sum();
+        if magic_number != MAGIC_NUMBER_FREE_LIST || page.header_view().check_sum().read() != check_sum {
+            return Err(DiskError::InvalidFreeList(page_id));
+        }
+        let trunk = Self { page };
+        if trunk.len() > trunk.capacity() {
+            return Err(DiskError::InvalidFreeList(page_id));
+        }
+
+        Ok(trunk)
+    }
+
+    pub fn next_trunk_page_id(&self) -> PageId {
+        PageId(trunk::View::new(self.page.body_view()).next_trunk_page_id().read())
+    }
+
+    pub fn len(&self) -> usize {
+        trunk::View::new(self.page.body_view()).number_of_leaves().read() as usize
+    }
+
+    pub fn capacity(&self) -> usize {
+        trunk_capacity(self.page.page_size())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity()
+    }
+
+    pub fn push(&mut self, page_id: PageId) {
+        let len = self.len();
+        let mut view = trunk::View::new(self.page.body_view_mut());
+        view.leaves_mut()[len * 4..(len + 1) * 4].copy_from_slice(&page_id.to_u32().to_be_bytes());
+        view.number_of_leaves_mut().write(len as u32 + 1);
+    }
+
+    pub fn pop(&mut self) -> Option<PageId> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let mut view = trunk::View::new(self.page.body_view_mut());
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&view.leaves()[(len - 1) * 4..len * 4]);
+        view.number_of_leaves_mut().write(len as u32 - 1);
+
+        Some(PageId(u32::from_be_bytes(bytes)))
+    }
+
+    pub fn page_ids(&self) -> Vec<PageId> {
+        let view = trunk::View::new(self.page.body_view());
+        view.leaves()[..self.len() * 4].chunks(4)
+            .map(|bytes| PageId(u32::from_be_bytes(bytes.try_into().unwrap())))
+            .collect()
+    }
+
+    pub fn into_page(mut self) -> SlottedPage {
+        let sum = self.page.check_sum();
+        self.page.header_view_mut().check_sum_mut().write(sum);
+        self.page
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::slotted_page::{MAGIC_NUMBER_META, PAGE_SIZE, PAGE_SIZES};
+
+    use super::*;
+
+    #[test]
+    fn test_push_and_pop() {
+        for page_size in PAGE_SIZES {
+            let capacity = trunk_capacity(page_size);
+            let mut trunk = FreeListTrunk::new(page_size, PageId(9)).unwrap();
+            assert_eq!(trunk.len(), 0);
+            assert_eq!(trunk.pop(), None);
+            for i in 1..=capacity {
+                assert_eq!(trunk.is_full(), false);
+                trunk.push(PageId(i as u32 + 10));
+            }
+            assert_eq!(trunk.is_full(), true);
+
+            let ret = FreeListTrunk::from_page(PageId(1), trunk.into_page());
+            assert_eq!(ret.is_ok(), true);
+            let mut trunk = ret.unwrap();
+            assert_eq!(trunk.next_trunk_page_id(), PageId(9));
+            assert_eq!(trunk.len(), capacity);
+            for i in (1..=capacity).rev() {
+                assert_eq!(trunk.pop(), Some(PageId(i as u32 + 10)));
+            }
+            assert_eq!(trunk.pop(), None);
+        }
+    }
+
+    #[test]
+    fn test_reject_other_pages() {
+        let ret = FreeListTrunk::from_page(PageId(1), SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_META).unwrap());
+        assert_eq!(matches!(ret, Err(DiskError::InvalidFreeList(PageId(1)))), true);
+    }
+}
@@ -0,0 +1,218 @@
+use binary_layout::{define_layout, Field};
+
+use crate::btree::slotted_page::{is_valid_page_size, MAGIC_NUMBER_META, page_header, PAGE_VERSION_V1, SlottedPage};
+use crate::disk_manager::{DiskError, PageId};
+
+/*
+ The file header lives in the body of the reserved meta page (PageId(0))
+ -------------------------------------------------------------------
+ |                      Format version (4b)                        |
+ -------------------------------------------------------------------
+ |                         Page size (4b)                          |
+ -------------------------------------------------------------------
+ |                       Next page id (4b)                         |
+ -------------------------------------------------------------------
+ |                       Root page id (4b)                         |
+ -------------------------------------------------------------------
+ |                    Free list head page id (4b)                  |
+ -------------------------------------------------------------------
+ |              Number of double-write slots (4b)                  |
+ -------------------------------------------------------------------
+ |                      Allocated end (4b)                         |
+ -------------------------------------------------------------------
+ |                      Checkpoint id (4b)                         |
+ -------------------------------------------------------------------
+ |                    Pages per segment (4b)                       |
+ -------------------------------------------------------------------
+ */
+
+// Files of V1 were written with V1 pages, see PAGE_VERSION_V1
+pub const FORMAT_VERSION_V1: u32 = 1;
+pub const FORMAT_VERSION_V2: u32 = 2;
+pub const HEADER_PAGE_ID: PageId = PageId(0);
+
+define_layout!(file_header, BigEndian, {
+    format_version: u32,
+    page_size: u32,
+    next_page_id: u32,
+    root_page_id: u32,
+    free_list_head: u32,
+    double_write_slots: u32,
+    allocated_end: u32,
+    checkpoint_id: u32,
+    pages_per_segment: u32,
+});
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FileHeader {
+    pub format_version: u32,
+    pub page_size: u32,
+    pub next_page_id: PageId,
+    pub root_page_id: PageId,
+    pub free_list_head: PageId,
+    // 0 when the double-write area is disabled
+    pub double_write_slots: u32,
+    // The file is grown in extents, and pages in [next_page_id, allocated_end) are preallocated
+    // but not handed out yet
+    pub allocated_end: PageId,
+    // The sequence number of the last completed checkpoint, and every page written before it is
+    // on the disk. 0 when the database has never been checkpointed, as in files written before
+    // checkpoints were introduced.
+    pub checkpoint_id: u32,
+    // The store is split into segment files of this many pages, and 0 keeps it in a single file
+    pub pages_per_segment: u32,
+}
+
+impl FileHeader {
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            format_version: FORMAT_VERSION_V2,
+            page_size: page_size as u32,
+            // PageId(0) is the header itself
+            next_page_id: PageId(1),
+            root_page_id: PageId(0),
+            free_list_head: PageId(0),
+            double_write_slots: 0,
+            allocated_end: PageId(1),
+            checkpoint_id: 0,
+            pages_per_segment: 0,
+        }
+    }
+
+    pub fn from_page(page: &mut SlottedPage) -> Result<Self, DiskError> {
+        let magic_number = page.header_view().magic_number().read();
+        if magic_number != MAGIC_NUMBER_META {
+            return Err(DiskError::InvalidHeader(format!("unexpected magic number {:#x}", magic_number)));
+        }
+        // A V1 page keeps its version where the page id is now, which is always 0 for the header page
+        if page.to_bytes()[page_header::page_id::OFFSET] == PAGE_VERSION_V1 {
+            return Err(DiskError::UnsupportedVersion(FORMAT_VERSION_V1));
+        }
+        let check_sum = page.check_sum();
+        if page.header_view().check_sum().read() != check_sum {
+            return Err(DiskError::InvalidHeader("check sum mismatch".to_string()));
+        }
+
+        let view = file_header::View::new(page.body_view());
+        let header = Self {
+            format_version: view.format_version().read(),
+            page_size: view.page_size().read(),
+            next_page_id: PageId(view.next_page_id().read()),
+            root_page_id: PageId(view.root_page_id().read()),
+            free_list_head: PageId(view.free_list_head().read()),
+            double_write_slots: view.double_write_slots().read(),
+            allocated_end: PageId(view.allocated_end().read()),
+            checkpoint_id: view.checkpoint_id().read(),
+            pages_per_segment: view.pages_per_segment().read(),
+        };
+        if header.format_version != FORMAT_VERSION_V2 {
+            return Err(DiskError::UnsupportedVersion(header.format_version));
+        }
+        if header.page_size != page.page_size() as u32 {
+            return Err(DiskError::PageSizeMismatch(header.page_size));
+        }
+        if header.next_page_id.to_u32() == 0 {
+            return Err(DiskError::InvalidHeader("next page id overlaps the header page".to_string()));
+        }
+
+        Ok(header)
+    }
+
+    pub fn to_page(self) -> Result<SlottedPage, DiskError> {
+        let mut page = SlottedPage::new(self.page_size as usize, MAGIC_NUMBER_META)?;
+        let mut view = file_header::View::new(page.body_view_mut());
+        view.format_version_mut().write(self.format_version);
+        view.page_size_mut().write(self.page_size);
+        view.next_page_id_mut().write(self.next_page_id.to_u32());
+        view.root_page_id_mut().write(self.root_page_id.to_u32());
+        view.free_list_head_mut().write(self.free_list_head.to_u32());
+        view.double_write_slots_mut().write(self.double_write_slots);
+        view.allocated_end_mut().write(self.allocated_end.to_u32());
+        view.checkpoint_id_mut().write(self.checkpoint_id);
+        view.pages_per_segment_mut().write(self.pages_per_segment);
+
+        let sum = page.check_sum();
+        page.header_view_mut().check_sum_mut().write(sum);
+
+        Ok(page)
+    }
+}
+
+// Stores read the page size from the head of the file before they can read any page.
+// The rest of the header is validated by DiskManager.
+pub fn peek_page_size(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < page_header::SIZE.unwrap() + file_header::SIZE.unwrap() {
+        return None;
+    }
+    if page_header::View::new(bytes).magic_number().read() != MAGIC_NUMBER_META {
+        return None;
+    }
+    let page_size = file_header::View::new(&bytes[page_header::SIZE.unwrap()..]).page_size().read() as usize;
+    is_valid_page_size(page_size).then_some(page_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::slotted_page::{PAGE_SIZE, PAGE_SIZES};
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut header = FileHeader::new(PAGE_SIZE);
+        header.next_page_id = PageId(42);
+        header.root_page_id = PageId(7);
+        header.free_list_head = PageId(3);
+        header.double_write_slots = 16;
+        header.allocated_end = PageId(64);
+        header.checkpoint_id = 5;
+        header.pages_per_segment = 256;
+
+        let mut page = header.to_page().unwrap();
+        assert_eq!(page.header_view().magic_number().read(), MAGIC_NUMBER_META);
+        let ret = FileHeader::from_page(&mut page);
+        assert_eq!(ret.is_ok(), true);
+        assert_eq!(ret.unwrap(), header);
+    }
+
+    #[test]
+    fn test_reject_invalid_page() {
+        let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_META).unwrap();
+        // body is zeroed, so the format version is 0
+        let ret = FileHeader::from_page(&mut page);
+        assert_eq!(matches!(ret, Err(DiskError::UnsupportedVersion(0))), true);
+
+        // A file written with V1 pages, and with the V1 format
+        let mut page = FileHeader::new(PAGE_SIZE).to_page().unwrap();
+        page.to_bytes_mut()[page_header::page_id::OFFSET] = PAGE_VERSION_V1;
+        let ret = FileHeader::from_page(&mut page);
+        assert_eq!(matches!(ret, Err(DiskError::UnsupportedVersion(FORMAT_VERSION_V1))), true);
+        let mut header = FileHeader::new(PAGE_SIZE);
+        header.format_version = FORMAT_VERSION_V1;
+        let ret = FileHeader::from_page(&mut header.to_page().unwrap());
+        assert_eq!(matches!(ret, Err(DiskError::UnsupportedVersion(FORMAT_VERSION_V1))), true);
+
+        let mut page = FileHeader::new(PAGE_SIZE).to_page().unwrap();
+        page.body_view_mut()[0] = 0xff;
+        let ret = FileHeader::from_page(&mut page);
+        assert_eq!(matches!(ret, Err(DiskError::InvalidHeader(_))), true);
+
+        // The page size in the header has to match the size of the page it is read from
+        let mut page = FileHeader::new(PAGE_SIZE).to_page().unwrap();
+        file_header::View::new(page.body_view_mut()).page_size_mut().write(PAGE_SIZE as u32 * 2);
+        let sum = page.check_sum();
+        page.header_view_mut().check_sum_mut().write(sum);
+        let ret = FileHeader::from_page(&mut page);
+        assert_eq!(matches!(ret, Err(DiskError::PageSizeMismatch(8192))), true);
+    }
+
+    #[test]
+    fn test_peek_page_size() {
+        for page_size in PAGE_SIZES {
+            let page = FileHeader::new(page_size).to_page().unwrap();
+            assert_eq!(peek_page_size(&page.to_bytes()[..PAGE_SIZE]), Some(page_size));
+        }
+        assert_eq!(peek_page_size(SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_META).unwrap().to_bytes()), None);
+        assert_eq!(peek_page_size(&[0; PAGE_SIZE]), None);
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::Result;
+
+use crate::btree::slotted_page::{is_valid_page_size, PAGE_SIZE};
+use crate::disk_manager::{check_buffer_size, DiskError, PageId, PageStore};
+
+// Keeps every page on the heap, for tests and ephemeral databases
+pub struct MemoryStore {
+    pages: Vec<Box<[u8]>>,
+    page_size: usize,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            pages: vec![],
+            page_size: PAGE_SIZE,
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_page_size(page_size: usize) -> Result<Self, DiskError> {
+        if !is_valid_page_size(page_size) {
+            return Err(DiskError::UnsupportedPageSize(page_size));
+        }
+        Ok(Self {
+            pages: vec![],
+            page_size,
+        })
+    }
+}
+
+impl PageStore for MemoryStore {
+    fn read_page(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()> {
+        check_buffer_size(buf, self.page_size)?;
+        let page = self.pages.get(page_id.to_u32() as usize).ok_or(DiskError::PageNotAllocated(page_id))?;
+        buf.copy_from_slice(&page[..]);
+
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_id: PageId, buf: &[u8]) -> Result<()> {
+        check_buffer_size(buf, self.page_size)?;
+        let page = self.pages.get_mut(page_id.to_u32() as usize).ok_or(DiskError::PageNotAllocated(page_id))?;
+        page.copy_from_slice(buf);
+
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<PageId> {
+        let page_id = PageId(self.pages.len() as u32);
+        self.pages.push(vec![0; self.page_size].into_boxed_slice());
+
+        Ok(page_id)
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        self.pages.truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.pages.len() as u64
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let mut store = MemoryStore::new();
+        let mut buf = [0; PAGE_SIZE];
+        assert_eq!(store.write_page(PageId(0), &buf).is_err(), true);
+
+        assert_eq!(store.allocate().unwrap(), PageId(0));
+        assert_eq!(store.allocate().unwrap(), PageId(1));
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.write_page(PageId(1), &[0xcd; PAGE_SIZE]).is_ok(), true);
+        assert_eq!(store.read_page(PageId(1), &mut buf).is_ok(), true);
+        assert_eq!(buf, [0xcd; PAGE_SIZE]);
+
+        let err = store.read_page(PageId(2), &mut buf).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(PageId(2)))), true);
+
+        // A buffer of another size is rejected
+        let err = store.write_page(PageId(1), &buf[..PAGE_SIZE / 2]).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageSizeMismatch(_))), true);
+        let err = store.read_pages(&mut [(PageId(1), &mut buf[1..])]).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageSizeMismatch(_))), true);
+
+        assert_eq!(matches!(MemoryStore::with_page_size(PAGE_SIZE + 1), Err(DiskError::UnsupportedPageSize(_))), true);
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use memmap2::MmapMut;
+
+
+use crate::disk_manager::{check_buffer_size, DiskError, PageId, PageStore};
+use crate::disk_manager::file_store::{detect_page_size, lock_file};
+
+// The mapping grows by this many pages at least, so that allocation does not remap every time
+const MIN_GROWTH_PAGES: u64 = 16;
+
+pub struct MmapStore {
+    file: File,
+    // None while the file is empty, since a zero-length mapping is not allowed
+    mmap: Option<MmapMut>,
+    number_of_pages: u64,
+    capacity: u64,
+    page_size: usize,
+}
+
+impl MmapStore {
+    pub fn open(file_path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_page_size(file_path, None)
+    }
+
+    // The page size applies to a new file, see FileOptions::page_size
+    pub fn with_page_size(file_path: impl AsRef<Path>, page_size: Option<usize>) -> Result<Self> {
+        let err = format!("failed to open file, file_path: {:?}", file_path.as_ref());
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file_path.as_ref())
+            .context(err)?;
+        lock_file(&file, file_path.as_ref(), false)?;
+        let page_size = detect_page_size(&file, page_size)?;
+        let number_of_pages = file.metadata().context("failed to read the file metadata")?.len() / page_size as u64;
+        let mut store = Self {
+            file,
+            mmap: None,
+            number_of_pages,
+            capacity: 0,
+            page_size,
+        };
+        store.remap(number_of_pages)?;
+
+        Ok(store)
+    }
+
+    fn remap(&mut self, capacity: u64) -> Result<()> {
+        if let Some(mmap) = self.mmap.take() {
+            mmap.flush().context("failed to flush the mapping")?;
+        }
+        self.file.set_len(capacity * self.page_size as u64).context("failed to resize the file")?;
+        if capacity > 0 {
+            // SAFETY: the file is owned by this store and is never resized while the mapping is alive
+            let mmap = unsafe { MmapMut::map_mut(&self.file) }.context("failed to map the file")?;
+            self.mmap = Some(mmap);
+        }
+        self.capacity = capacity;
+
+        Ok(())
+    }
+
+    fn page_range(&self, page_id: PageId) -> Result<std::ops::Range<usize>> {
+        if page_id.to_u64() >= self.number_of_pages {
+            return Err(DiskError::PageNotAllocated(page_id).into());
+        }
+        let start = page_id.offset(self.page_size) as usize;
+        Ok(start..start + self.page_size)
+    }
+}
+
+impl PageStore for MmapStore {
+    fn read_page(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()> {
+        check_buffer_size(buf, self.page_size)?;
+        let range = self.page_range(page_id)?;
+        buf.copy_from_slice(&self.mmap.as_ref().unwrap()[range]);
+
+        Ok(())
+    }
+
+    fn write_page(&mut self, page_id: PageId, buf: &[u8]) -> Result<()> {
+        check_buffer_size(buf, self.page_size)?;
+        let range = self.page_range(page_id)?;
+        self.mmap.as_mut().unwrap()[range].copy_from_slice(buf);
+
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<PageId> {
+        if self.number_of_pages >= self.capacity {
+            let capacity = (self.capacity * 2).max(self.capacity + MIN_GROWTH_PAGES);
+            self.remap(capacity)?;
+        }
+        let page_id = PageId(self.number_of_pages as u32);
+        self.number_of_pages += 1;
+
+        Ok(page_id)
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        if len >= self.number_of_pages {
+            return Ok(());
+        }
+        self.number_of_pages = len;
+        self.remap(len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        match self.mmap.as_ref() {
+            Some(mmap) => mmap.flush().context("failed to flush the mapping"),
+            None => Ok(()),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.number_of_pages
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+}
+
+impl Drop for MmapStore {
+    // Give the unused capacity back, otherwise it would count as allocated pages on the next open
+    fn drop(&mut self) {
+        if let Some(mmap) = self.mmap.take() {
+            let _ = mmap.flush();
+        }
+        let _ = self.file.set_len(self.number_of_pages * self.page_size as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::slotted_page::PAGE_SIZE;
+
+    use super::*;
+
+    #[test]
+    fn test_read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+
+        let mut store = MmapStore::open(&path).unwrap();
+        let mut buf = [0; PAGE_SIZE];
+        assert_eq!(store.read_page(PageId(0), &mut buf).is_err(), true);
+
+        // Grow over the initial capacity
+        for i in 0..(MIN_GROWTH_PAGES * 3) {
+            let page_id = store.allocate().unwrap();
+            assert_eq!(page_id, PageId(i as u32));
+            assert_eq!(store.write_page(page_id, &[i as u8; PAGE_SIZE]).is_ok(), true);
+        }
+        assert_eq!(store.sync().is_ok(), true);
+        drop(store);
+
+        let mut store = MmapStore::open(&path).unwrap();
+        assert_eq!(store.len(), MIN_GROWTH_PAGES * 3);
+        let err = MmapStore::open(&path).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::Locked(_))), true);
+        for i in 0..(MIN_GROWTH_PAGES * 3) {
+            assert_eq!(store.read_page(PageId(i as u32), &mut buf).is_ok(), true);
+            assert_eq!(buf, [i as u8; PAGE_SIZE]);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::HashSet;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use io_uring::{IoUring, opcode, squeue, types};
+
+use crate::btree::slotted_page::PAGE_ALIGNMENT;
+use crate::disk_manager::{check_buffer_size, DiskError, PageId, PageStore};
+use crate::disk_manager::file_store::{FileOptions, FileStore};
+
+const QUEUE_DEPTH: u32 = 64;
+// Not exported by the io_uring crate
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+// A FileStore which submits batches of page reads and writes through io_uring.
+// Single page operations and kernels without io_uring go through the synchronous FileStore.
+pub struct UringStore {
+    store: FileStore,
+    ring: Option<IoUring>,
+}
+
+impl UringStore {
+    pub fn open(file_path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_options(file_path, FileOptions::default())
+    }
+
+    pub fn with_options(file_path: impl AsRef<Path>, options: FileOptions) -> Result<Self> {
+        let store = FileStore::with_options(file_path, options)?;
+        // e.g. ENOSYS on old kernels, or EPERM when io_uring is disabled by sysctl or seccomp
+        let ring = IoUring::new(QUEUE_DEPTH).ok();
+
+        Ok(Self {
+            store,
+            ring,
+        })
+    }
+
+    pub fn is_uring_enabled(&self) -> bool {
+        self.ring.is_some()
+    }
+
+    fn check_allocated(&self, page_id: PageId) -> Result<()> {
+        if page_id.to_u64() >= self.store.len() {
+            return Err(DiskError::PageNotAllocated(page_id).into());
+        }
+        Ok(())
+    }
+
+    // O_DIRECT needs aligned buffers, which io_uring passes to the kernel as they are
+    fn can_submit(&self, buf: &[u8]) -> bool {
+        !self.store.is_direct_io() || (buf.as_ptr() as usize).is_multiple_of(PAGE_ALIGNMENT)
+    }
+
+    // Submits the entries in chunks of the queue depth and returns the results in the order of the entries.
+    // The caller keeps the buffers referenced by the entries alive until this returns, and this never returns
+    // while the kernel may still access them, even on an error.
+    fn submit(&mut self, entries: Vec<squeue::Entry>) -> Result<Vec<i32>> {
+        let ring = self.ring.as_mut().unwrap();
+        let mut results = vec![0; entries.len()];
+        for (chunk_index, chunk) in entries.chunks(QUEUE_DEPTH as usize).enumerate() {
+            let chunk: Vec<squeue::Entry> = chunk.iter().enumerate()
+                .map(|(i, entry)| entry.clone().user_data((chunk_index * QUEUE_DEPTH as usize + i) as u64))
+                .collect();
+            // Every entry of the previous chunk has completed, so the queue is empty here.
+            // SAFETY: the buffers outlive the submission, see above
+            unsafe {
+                ring.submission().push_multiple(&chunk).map_err(|_| anyhow!("submission queue is full"))?;
+            }
+            let mut completed = 0;
+            while completed < chunk.len() {
+                if let Err(err) = ring.submit_and_wait(chunk.len() - completed) {
+                    match err.raw_os_error() {
+                        // Interrupted by a signal, or the completion queue is full and reaped below
+                        Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => {}
+                        _ => {
+                            let in_flight = chunk.len() - completed - ring.submission().len();
+                            Self::wait_in_flight(ring, in_flight);
+                            // The entries the kernel has not taken stay in the queue, so the ring is never used again
+                            self.ring = None;
+                            return Err(anyhow!(err).context("failed to submit io_uring entries"));
+                        }
+                    }
+                }
+                for cqe in ring.completion() {
+                    results[cqe.user_data() as usize] = cqe.result();
+                    completed += 1;
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    // Waits for the entries which the kernel has taken, without submitting the others.
+    // It gives up only when the ring cannot be entered at all, and then nothing is left to wait for.
+    fn wait_in_flight(ring: &mut IoUring, mut in_flight: usize) {
+        while in_flight > 0 {
+            // SAFETY: nothing is submitted, and no argument is passed
+            let ret = unsafe { ring.submitter().enter::<libc::sigset_t>(0, in_flight as u32, IORING_ENTER_GETEVENTS, None) };
+            if let Err(err) = ret {
+                if err.raw_os_error() != Some(libc::EINTR) {
+                    return;
+                }
+            }
+            in_flight -= ring.completion().count().min(in_flight);
+        }
+    }
+
+    fn check_result(&self, page_id: PageId, result: i32) -> Result<()> {
+        if result < 0 {
+            let err = io::Error::from_raw_os_error(-result);
+            return Err(anyhow!(err).context(format!("failed to access the page {:?}", page_id)));
+        }
+        if result as usize != self.store.page_size() {
+            return Err(anyhow!("short I/O on the page {:?}: {} bytes", page_id, result));
+        }
+        Ok(())
+    }
+}
+
+impl PageStore for UringStore {
+    fn read_page(&mut self, page_id: PageId, buf: &mut [u8]) -> Result<()> {
+        self.store.read_page(page_id, buf)
+    }
+
+    fn write_page(&mut self, page_id: PageId, buf: &[u8]) -> Result<()> {
+        self.store.write_page(page_id, buf)
+    }
+
+    fn read_pages(&mut self, requests: &mut [(PageId, &mut [u8])]) -> Result<()> {
+        for (page_id, buf) in requests.iter() {
+            check_buffer_size(buf, self.store.page_size())?;
+            self.check_allocated(*page_id)?;
+        }
+        if self.ring.is_none() || !requests.iter().all(|(_, buf)| self.can_submit(buf)) {
+            for (page_id, buf) in requests.iter_mut() {
+                self.store.read_page(*page_id, buf)?;
+            }
+            return Ok(());
+        }
+
+        let page_size = self.store.page_size() as u32;
+        let entries = requests.iter_mut()
+            .map(|(page_id, buf)| {
+                let (file, offset) = self.store.locate(*page_id);
+                opcode::Read::new(types::Fd(file.as_raw_fd()), buf.as_mut_ptr(), page_size).offset(offset).build()
+            })
+            .collect();
+        let results = self.submit(entries)?;
+        for ((page_id, _), result) in requests.iter().zip(results) {
+            self.check_result(*page_id, result)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_pages(&mut self, requests: &[(PageId, &[u8])]) -> Result<()> {
+        // The entries tell the kernel to access page_size bytes of each buffer
+        for (_, buf) in requests {
+            check_buffer_size(buf, self.store.page_size())?;
+        }
+        if self.ring.is_none() || !requests.iter().all(|(_, buf)| self.can_submit(buf)) {
+            return self.store.write_pages(requests);
+        }
+
+        // The kernel may complete writes in any order, so only the last write of a page is submitted
+        let mut seen = HashSet::new();
+        let mut failures = vec![];
+        let mut submitted = vec![];
+        for &(page_id, buf) in requests.iter().rev() {
+            if !seen.insert(page_id) {
+                continue;
+            }
+            match self.check_allocated(page_id) {
+                Ok(_) => submitted.push((page_id, buf)),
+                Err(err) => failures.push((page_id, err)),
+            }
+        }
+        let page_size = self.store.page_size() as u32;
+        let entries = submitted.iter()
+            .map(|(page_id, buf)| {
+                let (file, offset) = self.store.locate(*page_id);
+                opcode::Write::new(types::Fd(file.as_raw_fd()), buf.as_ptr(), page_size).offset(offset).build()
+            })
+            .collect();
+        let results = self.submit(entries)?;
+        for ((page_id, _), result) in submitted.iter().zip(results) {
+            if let Err(err) = self.check_result(*page_id, result) {
+                failures.push((*page_id, err));
+            }
+        }
+        if !failures.is_empty() {
+            return Err(DiskError::PartialWrite(failures).into());
+        }
+
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<PageId> {
+        self.store.allocate()
+    }
+
+    fn allocate_pages(&mut self, count: usize) -> Result<PageId> {
+        self.store.allocate_pages(count)
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        self.store.truncate(len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.store.sync()
+    }
+
+    fn len(&self) -> u64 {
+        self.store.len()
+    }
+
+    fn page_size(&self) -> usize {
+        self.store.page_size()
+    }
+
+    fn pages_per_segment(&self) -> u32 {
+        self.store.pages_per_segment()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, PAGE_SIZE, SlottedPage};
+
+    use super::*;
+
+    #[test]
+    fn test_batch_read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+        // More than the queue depth, so that the batch is split into chunks
+        let number_of_pages = QUEUE_DEPTH as usize * 2 + 3;
+
+        let mut store = UringStore::open(&path).unwrap();
+        let mut pages = vec![];
+        for i in 0..number_of_pages {
+            let page_id = store.allocate().unwrap();
+            let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+            page.add_cell(0, &(i as u32).to_be_bytes(), b"value").unwrap();
+            pages.push((page_id, page));
+        }
+        let requests: Vec<(PageId, &[u8])> = pages.iter().map(|(page_id, page)| (*page_id, page.to_bytes())).collect();
+        assert_eq!(store.write_pages(&requests).is_ok(), true);
+        assert_eq!(store.sync().is_ok(), true);
+
+        let mut fetched: Vec<SlottedPage> = (0..number_of_pages).map(|_| SlottedPage::zeroed(PAGE_SIZE).unwrap()).collect();
+        // Read in the reverse order
+        let mut requests: Vec<(PageId, &mut [u8])> = fetched.iter_mut().enumerate()
+            .map(|(i, page)| (PageId((number_of_pages - i - 1) as u32), page.to_bytes_mut()))
+            .collect();
+        assert_eq!(store.read_pages(&mut requests).is_ok(), true);
+        for (i, page) in fetched.iter_mut().enumerate() {
+            assert_eq!(page.valid(), true);
+            assert_eq!(page.cell_view(0).body()[0..4], ((number_of_pages - i - 1) as u32).to_be_bytes());
+        }
+
+        let mut buf = [0; PAGE_SIZE];
+        let mut requests = vec![(PageId(number_of_pages as u32), &mut buf[..])];
+        let err = store.read_pages(&mut requests).err().unwrap();
+        assert_eq!(matches!(err.downcast_ref::<DiskError>(), Some(DiskError::PageNotAllocated(_))), true);
+    }
+
+    #[test]
+    fn test_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.idb");
+
+        let mut store = UringStore::open(&path).unwrap();
+        store.ring = None;
+        let page_id = store.allocate().unwrap();
+        let page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+        assert_eq!(store.write_pages(&[(page_id, page.to_bytes())]).is_ok(), true);
+        let mut fetched = SlottedPage::zeroed(PAGE_SIZE).unwrap();
+        assert_eq!(store.read_pages(&mut [(page_id, fetched.to_bytes_mut())]).is_ok(), true);
+        assert_eq!(fetched.valid(), true);
+    }
+}
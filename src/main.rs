@@ -4,6 +4,8 @@ mod btree;
 mod access_manager;
 mod buffer_manager;
 mod disk_manager;
+#[cfg(test)]
+mod test_util;
 
 fn print(a: [i32; 3]) {
     println!("{:?}", a)
@@ -0,0 +1,30 @@
+use crate::btree::slotted_page::{MAGIC_NUMBER_LEAF, PAGE_SIZE, SlottedPage};
+use crate::disk_manager::{DiskManager, PageId, PageStore};
+
+// Indices below bound in a pseudo-random order, which is the same on every run for the seed
+pub fn random_indices(mut seed: u32, bound: usize) -> impl Iterator<Item = usize> {
+    std::iter::repeat_with(move || {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as usize % bound
+    })
+}
+
+// An empty leaf page, or a leaf page with a single cell
+pub fn leaf_page(cell: Option<(&[u8], &[u8])>) -> SlottedPage {
+    let mut page = SlottedPage::new(PAGE_SIZE, MAGIC_NUMBER_LEAF).unwrap();
+    if let Some((key, value)) = cell {
+        page.add_cell(0, key, value).unwrap();
+    }
+    page
+}
+
+// Allocates and writes a page for each of the pages, and returns the page ids in the same order
+pub fn write_new_pages<S: PageStore>(disk_manager: &mut DiskManager<S>, pages: impl IntoIterator<Item = SlottedPage>) -> Vec<PageId> {
+    pages.into_iter()
+        .map(|page| {
+            let page_id = disk_manager.allocate_page().unwrap();
+            disk_manager.write_page(page_id, &page).unwrap();
+            page_id
+        })
+        .collect()
+}